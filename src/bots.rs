@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::ffi;
+
+/// Name of the fake client used when a stream targets client_index -1.
+pub const DEFAULT_NAME: &str = "Sympho";
+
+struct Bot {
+    /// Slot and userid of the bot while it is in game.
+    client: Option<(i32, i32)>,
+    team: i32,
+    last_active: Instant,
+}
+
+/// Snapshot of a bot for the API.
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub name: String,
+    pub client_index: Option<i32>,
+    pub team: i32,
+    pub idle: Duration,
+}
+
+/// Fake clients created by the extension, one per voice identity.
+///
+/// Bots join the configured team, are kicked once they have been idle for too long or
+/// the extension unloads, and are created again on the next map if the engine dropped
+/// them while they were in use.
+pub struct Registry {
+    bots: HashMap<String, Bot>,
+    idle_timeout: Duration,
+    team: i32,
+    restore: bool,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            bots: HashMap::new(),
+            idle_timeout: Duration::from_secs(300),
+            team: 0,
+            restore: false,
+        }
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn configure(&mut self, config: &Config) {
+        self.idle_timeout = config.bot_idle_timeout;
+        self.team = config.bot_team;
+    }
+
+    /// Returns the slot of the bot called `name`, creating it if it is not in game.
+    pub fn resolve(&mut self, name: &str, now: Instant) -> Option<i32> {
+        let team = self.team;
+        let bot = self.bots.entry(name.to_string()).or_insert(Bot {
+            client: None,
+            team: 0,
+            last_active: now,
+        });
+        bot.last_active = now;
+
+        if !bot.validate() {
+            bot.spawn(name);
+        }
+        bot.assign_team(team);
+
+        bot.client.map(|(client_index, _)| client_index)
+    }
+
+    /// Whether the client `userid` in `client_index` is one of the extension's bots.
+    pub fn owns(&self, client_index: i32, userid: i32) -> bool {
+        self.bots
+            .values()
+            .any(|bot| bot.client == Some((client_index, userid)))
+    }
+
+    /// Kicks bots that have not spoken for longer than the idle timeout.
+    pub fn kick_idle(&mut self, now: Instant) {
+        if self.idle_timeout == Duration::ZERO {
+            return;
+        }
+
+        let idle_timeout = self.idle_timeout;
+        self.bots.retain(|_, bot| {
+            if now.saturating_duration_since(bot.last_active) < idle_timeout {
+                return true;
+            }
+
+            bot.kick("Idle");
+            false
+        });
+    }
+
+    /// Kicks every bot, used when the extension unloads.
+    pub fn kick_all(&mut self) {
+        for (_, mut bot) in self.bots.drain() {
+            bot.kick("VoiceServer unloaded");
+        }
+    }
+
+    /// Schedules the bots of the previous map to be created again.
+    pub fn on_map_start(&mut self) {
+        self.restore = true;
+    }
+
+    /// Recreates bots that did not survive a map change, once the server is running frames
+    /// again.
+    ///
+    /// Bots that are still in game under their userid are kept, so a bot is never created
+    /// twice.
+    pub fn restore(&mut self) {
+        if !std::mem::take(&mut self.restore) {
+            return;
+        }
+
+        let team = self.team;
+        for (name, bot) in self.bots.iter_mut() {
+            if !bot.validate() {
+                bot.spawn(name);
+            }
+            bot.assign_team(team);
+        }
+    }
+
+    pub fn list(&self, now: Instant) -> Vec<Info> {
+        let mut list: Vec<Info> = self
+            .bots
+            .iter()
+            .map(|(name, bot)| Info {
+                name: name.clone(),
+                client_index: bot.client.map(|(client_index, _)| client_index),
+                team: bot.team,
+                idle: now.saturating_duration_since(bot.last_active),
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+
+        list
+    }
+}
+
+impl Bot {
+    /// Checks that the bot is still in game in its slot, refreshing its team.
+    fn validate(&mut self) -> bool {
+        let (client_index, userid) = match self.client {
+            Some(client) => client,
+            None => return false,
+        };
+
+        let info = ffi::get_client_info(client_index);
+        if !info.in_game || info.userid != userid {
+            self.client = None;
+            return false;
+        }
+        self.team = info.team;
+
+        true
+    }
+
+    fn spawn(&mut self, name: &str) {
+        let client_index = ffi::create_fake_client(name);
+        if client_index < 0 {
+            self.client = None;
+            return;
+        }
+
+        let info = ffi::get_client_info(client_index);
+        self.client = Some((client_index, info.userid));
+        self.team = info.team;
+    }
+
+    fn assign_team(&mut self, team: i32) {
+        if let Some((client_index, _)) = self.client {
+            if team != 0 && self.team != team {
+                ffi::change_client_team(client_index, team);
+                self.team = team;
+            }
+        }
+    }
+
+    fn kick(&mut self, reason: &str) {
+        if let Some((_, userid)) = self.client.take() {
+            ffi::kick_client(userid, reason);
+        }
+    }
+}
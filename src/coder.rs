@@ -1,3 +1,12 @@
+use crate::resample::Resampler;
+
+/// Sample rate of the engine's CELT custom mode.
+pub const SAMPLE_RATE: u32 = 22050;
+/// Number of PCM samples carried by one CELT frame.
+pub const FRAME_SIZE: usize = 512;
+/// Size in bytes of one encoded CELT frame.
+pub const PACKET_SIZE: usize = 64;
+
 pub struct Decoder {
     decoder: *mut opuscelt_sys::OpusCustomDecoder,
     mode: *mut opuscelt_sys::OpusCustomMode,
@@ -6,7 +15,11 @@ pub struct Decoder {
 impl Decoder {
     pub fn new() -> Self {
         unsafe {
-            let mode = opuscelt_sys::opus_custom_mode_create(22050, 512, std::ptr::null_mut());
+            let mode = opuscelt_sys::opus_custom_mode_create(
+                SAMPLE_RATE as _,
+                FRAME_SIZE as _,
+                std::ptr::null_mut(),
+            );
             if mode.is_null() {
                 panic!("opus_custom_mode_create returns null");
             }
@@ -35,6 +48,24 @@ impl Decoder {
             Ok(())
         }
     }
+
+    /// Fills `output` with a guess at a lost frame, extrapolated from the frames before it.
+    pub fn conceal(&mut self, output: &mut [i16]) -> Result<(), i32> {
+        unsafe {
+            let ret = opuscelt_sys::opus_custom_decode(
+                self.decoder,
+                std::ptr::null(),
+                0,
+                output.as_mut_ptr(),
+                output.len() as _,
+            );
+            if ret < 0 {
+                return Err(ret);
+            }
+
+            Ok(())
+        }
+    }
 }
 
 impl Drop for Decoder {
@@ -56,7 +87,11 @@ pub struct Encoder {
 impl Encoder {
     pub fn new() -> Self {
         unsafe {
-            let mode = opuscelt_sys::opus_custom_mode_create(22050, 512, std::ptr::null_mut());
+            let mode = opuscelt_sys::opus_custom_mode_create(
+                SAMPLE_RATE as _,
+                FRAME_SIZE as _,
+                std::ptr::null_mut(),
+            );
             if mode.is_null() {
                 panic!("opus_custom_mode_create returns null");
             }
@@ -97,3 +132,297 @@ impl Drop for Encoder {
 }
 
 unsafe impl Send for Encoder {}
+
+/// Sample encoding of PCM sent by clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleType {
+    S16Le,
+    F32Le,
+}
+
+impl SampleType {
+    fn size(self) -> usize {
+        match self {
+            SampleType::S16Le => 2,
+            SampleType::F32Le => 4,
+        }
+    }
+
+    /// Reads one sample as a float in -1.0..=1.0.
+    fn read(self, bytes: &[u8]) -> f32 {
+        match self {
+            SampleType::S16Le => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            SampleType::F32Le => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    }
+}
+
+/// Layout of PCM sent by clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputFormat {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub sample_type: SampleType,
+}
+
+impl Default for InputFormat {
+    fn default() -> Self {
+        Self {
+            sample_rate: SAMPLE_RATE,
+            channels: 1,
+            sample_type: SampleType::S16Le,
+        }
+    }
+}
+
+impl InputFormat {
+    /// Averages the channels of one interleaved sample frame into a mono sample.
+    fn downmix(&self, bytes: &[u8]) -> i16 {
+        let sum: f32 = bytes
+            .chunks_exact(self.sample_type.size())
+            .map(|sample| self.sample_type.read(sample))
+            .sum();
+        let mono = sum / self.channels as f32;
+
+        (mono * 32768.0)
+            .round()
+            .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+}
+
+/// Splits a stream of client PCM that arrives in arbitrarily sized chunks into frames.
+///
+/// Input in any `InputFormat` is downmixed to mono and resampled to the engine rate first.
+/// Bytes that do not complete a sample frame, and samples that do not fill a whole frame,
+/// are held back until the next chunk; only `finish` pads the final partial frame with
+/// silence.
+pub struct Framer {
+    format: InputFormat,
+    resampler: Resampler,
+    partial: Vec<u8>,
+    pcm: Vec<i16>,
+}
+
+impl Default for Framer {
+    fn default() -> Self {
+        Self::with_format(InputFormat::default())
+    }
+}
+
+impl Framer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_format(format: InputFormat) -> Self {
+        Self {
+            format,
+            resampler: Resampler::new(format.sample_rate, SAMPLE_RATE),
+            partial: Vec::new(),
+            pcm: Vec::new(),
+        }
+    }
+
+    /// Appends `data` and returns every whole frame that is now available.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<i16>> {
+        let frame_bytes = self.format.sample_type.size() * self.format.channels;
+        self.partial.extend_from_slice(data);
+        let whole = self.partial.len() / frame_bytes * frame_bytes;
+
+        let mono: Vec<i16> = self.partial[..whole]
+            .chunks_exact(frame_bytes)
+            .map(|frame| self.format.downmix(frame))
+            .collect();
+        self.partial.drain(..whole);
+
+        self.push_samples(&mono)
+    }
+
+    /// Appends mono samples at the input rate and returns every whole frame now available.
+    pub fn push_samples(&mut self, samples: &[i16]) -> Vec<Vec<i16>> {
+        let pcm = self.resampler.process(samples);
+        self.pcm.extend_from_slice(&pcm);
+
+        self.take_frames()
+    }
+
+    /// Flushes the resampler and returns the remaining frames, the last padded to size.
+    pub fn finish(&mut self) -> Vec<Vec<i16>> {
+        self.partial.clear();
+        let pcm = self.resampler.flush();
+        self.pcm.extend_from_slice(&pcm);
+
+        let mut frames = self.take_frames();
+        if !self.pcm.is_empty() {
+            let mut frame = std::mem::take(&mut self.pcm);
+            frame.resize(FRAME_SIZE, 0);
+            frames.push(frame);
+        }
+
+        frames
+    }
+
+    fn take_frames(&mut self) -> Vec<Vec<i16>> {
+        let frames = self.pcm.len() / FRAME_SIZE;
+        self.pcm
+            .drain(..frames * FRAME_SIZE)
+            .collect::<Vec<_>>()
+            .chunks(FRAME_SIZE)
+            .map(|frame| frame.to_vec())
+            .collect()
+    }
+}
+
+/// Sample rate of standard Opus streams.
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+/// Number of PCM samples in a 20 ms Opus frame.
+pub const OPUS_FRAME_SIZE: usize = 960;
+/// Largest packet a single Opus frame can produce.
+pub const OPUS_MAX_PACKET_SIZE: usize = 1275;
+/// Number of PCM samples in the longest Opus packet, 120 ms.
+pub const OPUS_MAX_FRAME_SIZE: usize = 5760;
+
+pub struct OpusEncoder {
+    encoder: *mut opuscelt_sys::OpusEncoder,
+}
+
+impl OpusEncoder {
+    pub fn new() -> Self {
+        unsafe {
+            let encoder = opuscelt_sys::opus_encoder_create(
+                OPUS_SAMPLE_RATE as _,
+                1,
+                opuscelt_sys::OPUS_APPLICATION_VOIP as _,
+                std::ptr::null_mut(),
+            );
+            if encoder.is_null() {
+                panic!("opus_encoder_create returns null");
+            }
+
+            Self { encoder }
+        }
+    }
+
+    pub fn encode(&mut self, pcm: &[i16]) -> Result<Vec<u8>, i32> {
+        let mut output = vec![0; OPUS_MAX_PACKET_SIZE];
+        unsafe {
+            let ret = opuscelt_sys::opus_encode(
+                self.encoder,
+                pcm.as_ptr(),
+                pcm.len() as _,
+                output.as_mut_ptr(),
+                output.len() as _,
+            );
+            if ret < 0 {
+                return Err(ret);
+            }
+            output.truncate(ret as usize);
+
+            Ok(output)
+        }
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe {
+            opuscelt_sys::opus_encoder_destroy(self.encoder);
+        }
+    }
+}
+
+unsafe impl Send for OpusEncoder {}
+
+pub struct OpusDecoder {
+    decoder: *mut opuscelt_sys::OpusDecoder,
+}
+
+impl OpusDecoder {
+    /// Creates a mono decoder; stereo packets are downmixed by libopus.
+    pub fn new() -> Self {
+        unsafe {
+            let decoder =
+                opuscelt_sys::opus_decoder_create(OPUS_SAMPLE_RATE as _, 1, std::ptr::null_mut());
+            if decoder.is_null() {
+                panic!("opus_decoder_create returns null");
+            }
+
+            Self { decoder }
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<i16>, i32> {
+        let mut output = vec![0; OPUS_MAX_FRAME_SIZE];
+        unsafe {
+            let ret = opuscelt_sys::opus_decode(
+                self.decoder,
+                data.as_ptr(),
+                data.len() as _,
+                output.as_mut_ptr(),
+                output.len() as _,
+                0,
+            );
+            if ret < 0 {
+                return Err(ret);
+            }
+            output.truncate(ret as usize);
+
+            Ok(output)
+        }
+    }
+}
+
+impl Drop for OpusDecoder {
+    fn drop(&mut self) {
+        unsafe {
+            opuscelt_sys::opus_decoder_destroy(self.decoder);
+        }
+    }
+}
+
+unsafe impl Send for OpusDecoder {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s16le(samples: impl IntoIterator<Item = i16>) -> Vec<u8> {
+        samples.into_iter().flat_map(i16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn framer_carries_partial_frames_across_chunks() {
+        let mut framer = Framer::new();
+        let data = s16le((0..FRAME_SIZE as i16 + 100).map(|i| i % 100));
+
+        // Split in the middle of a sample as well as in the middle of a frame.
+        let (first, rest) = data.split_at(301);
+        assert!(framer.push(first).is_empty());
+        let frames = framer.push(rest);
+
+        assert_eq!(frames.len(), 1);
+        let expected: Vec<i16> = (0..FRAME_SIZE as i16).map(|i| i % 100).collect();
+        assert_eq!(frames[0], expected);
+    }
+
+    #[test]
+    fn framer_pads_the_last_frame_on_finish() {
+        let mut framer = Framer::new();
+        assert!(framer.push(&s16le(vec![7; 100])).is_empty());
+
+        let frames = framer.finish();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].len(), FRAME_SIZE);
+        assert!(frames[0][..100].iter().all(|sample| *sample == 7));
+        assert!(frames[0][100..].iter().all(|sample| *sample == 0));
+        assert!(framer.finish().is_empty());
+    }
+
+    #[test]
+    fn framer_drops_an_incomplete_sample_on_finish() {
+        let mut framer = Framer::new();
+        framer.push(&[1]);
+
+        assert!(framer.finish().is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::ffi;
+
+/// Extension settings read from SourceMod's core.cfg.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How much audio a `SendVoiceData` stream may queue before reading from it pauses.
+    pub stream_buffer: Duration,
+    /// Hard limit on the audio a `SendVoiceData` stream may have queued at once.
+    ///
+    /// Reading pauses only between messages, so this is what bounds a single message
+    /// that decodes to a long stretch of audio.
+    pub max_buffered: Duration,
+    /// Gain applied to streams ducked by a higher-priority stream on the same target.
+    pub duck_gain: f32,
+    /// Lets every `SendVoiceData` caller and plugin speak as any client, not just the
+    /// extension's bots.
+    pub allow_impersonation: bool,
+    /// Bearer token that lets a caller speak as real players; empty disables it.
+    pub impersonation_token: String,
+    /// How long a bot created by the extension may stay silent before it is kicked.
+    pub bot_idle_timeout: Duration,
+    /// Team bots are moved to after joining, 0 to leave them where the game puts them.
+    pub bot_team: i32,
+    /// Directory `PlayFile` may read from; empty disables file playback.
+    pub file_root: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            stream_buffer: Duration::from_millis(500),
+            max_buffered: Duration::from_secs(10),
+            duck_gain: 0.25,
+            allow_impersonation: false,
+            impersonation_token: String::new(),
+            bot_idle_timeout: Duration::from_secs(300),
+            bot_team: 0,
+            file_root: String::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let default = Self::default();
+
+        Self {
+            stream_buffer: get_millis("VoiceServerStreamBufferMs", default.stream_buffer),
+            max_buffered: get_millis("VoiceServerMaxBufferedMs", default.max_buffered),
+            duck_gain: get("VoiceServerDuckGain", default.duck_gain),
+            allow_impersonation: get_bool(
+                "VoiceServerAllowImpersonation",
+                default.allow_impersonation,
+            ),
+            impersonation_token: ffi::get_config_value("VoiceServerImpersonationToken"),
+            bot_idle_timeout: get_millis("VoiceServerBotIdleTimeoutMs", default.bot_idle_timeout),
+            bot_team: get("VoiceServerBotTeam", default.bot_team),
+            file_root: ffi::get_config_value("VoiceServerFileRoot"),
+        }
+    }
+}
+
+fn get<T: FromStr>(key: &str, default: T) -> T {
+    let value = ffi::get_config_value(key);
+    if value.is_empty() {
+        return default;
+    }
+
+    match value.trim().parse() {
+        Ok(value) => value,
+        Err(_) => {
+            ffi::log_error(&format!("invalid value for {}: {}", key, value));
+            default
+        }
+    }
+}
+
+fn get_millis(key: &str, default: Duration) -> Duration {
+    Duration::from_millis(get(key, default.as_millis() as u64))
+}
+
+fn get_bool(key: &str, default: bool) -> bool {
+    let value = ffi::get_config_value(key);
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" => default,
+        "yes" | "true" | "on" | "1" => true,
+        "no" | "false" | "off" | "0" => false,
+        _ => {
+            ffi::log_error(&format!("invalid value for {}: {}", key, value));
+            default
+        }
+    }
+}
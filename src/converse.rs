@@ -0,0 +1,428 @@
+use std::collections::VecDeque;
+
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tonic::{Status, Streaming};
+
+use crate::bots;
+use crate::error::Result;
+use crate::input::Input;
+use crate::playout::StreamWriter;
+use crate::recv::RecvVoiceReceiver;
+use crate::voiceserver::{
+    converse_reply, converse_request, converse_response, converse_start, send_voice_request,
+    ConverseReply, ConverseRequest, ConverseResponse, ConverseStart, RecvVoiceRequest,
+    RecvVoiceResponse, ReplyEvent, ReplyState, TalkEventKind,
+};
+
+/// Subscription to the voice of the player a conversation listens to.
+pub fn voice_request(start: &ConverseStart) -> Result<RecvVoiceRequest> {
+    let mut request = RecvVoiceRequest {
+        encoding: start.encoding,
+        sample_rate: start.sample_rate,
+        talk_events: true,
+        talk_gap_ms: start.talk_gap_ms,
+        vad_level: start.vad_level,
+        ..RecvVoiceRequest::default()
+    };
+    match start.player {
+        Some(converse_start::Player::ClientIndex(client_index)) => {
+            request.client_indexes.push(client_index)
+        }
+        Some(converse_start::Player::Steamid(steamid)) => request.steamids.push(steamid),
+        None => return Err(Status::invalid_argument("conversation names no player").into()),
+    }
+
+    Ok(request)
+}
+
+/// Bot that speaks a conversation's replies.
+pub fn bot_name(start: &ConverseStart) -> String {
+    if start.bot_name.is_empty() {
+        bots::DEFAULT_NAME.to_string()
+    } else {
+        start.bot_name.clone()
+    }
+}
+
+fn payload(payload: converse_reply::Payload) -> Option<send_voice_request::Payload> {
+    match payload {
+        converse_reply::Payload::AudioData(data) if data.is_empty() => None,
+        converse_reply::Payload::OpusPacket(packet) if packet.is_empty() => None,
+        converse_reply::Payload::OggOpus(data) if data.is_empty() => None,
+        converse_reply::Payload::AudioData(data) => {
+            Some(send_voice_request::Payload::AudioData(data))
+        }
+        converse_reply::Payload::OpusPacket(packet) => {
+            Some(send_voice_request::Payload::OpusPacket(packet))
+        }
+        converse_reply::Payload::OggOpus(data) => Some(send_voice_request::Payload::OggOpus(data)),
+    }
+}
+
+enum Wake {
+    Voice(Option<Result<RecvVoiceResponse, Status>>),
+    Request(Option<Result<ConverseRequest, Status>>),
+    Drained,
+}
+
+/// A `Converse` stream: the player's voice goes out, and replies queue on one playout
+/// stream of the bot, one after another.
+///
+/// Replies are told apart by counting the frames queued since the queue was last cut, so
+/// a reply has played once fewer frames are waiting than were queued after it.
+pub struct Conversation {
+    sender: mpsc::Sender<Result<ConverseResponse, Status>>,
+    writer: StreamWriter,
+    barge_in: bool,
+    buffer_frames: usize,
+    /// Frames of replies that may be queued at once.
+    max_frames: usize,
+    /// Decoder of the reply being uploaded, from its first audio until it ends.
+    input: Option<Input>,
+    next_reply: u64,
+    /// Whether the reply being uploaded was cut off, so the rest of it is dropped.
+    discarding: bool,
+    /// Uploaded replies that have not played out yet, with the frame count each ends at.
+    replies: VecDeque<(u64, usize)>,
+    /// Frames queued since the queue was last cut.
+    pushed: usize,
+}
+
+impl Conversation {
+    pub fn new(
+        sender: mpsc::Sender<Result<ConverseResponse, Status>>,
+        writer: StreamWriter,
+        start: &ConverseStart,
+        buffer_frames: usize,
+        max_frames: usize,
+    ) -> Self {
+        Self {
+            sender,
+            writer,
+            barge_in: start.barge_in,
+            buffer_frames,
+            max_frames,
+            input: None,
+            next_reply: 0,
+            discarding: false,
+            replies: VecDeque::new(),
+            pushed: 0,
+        }
+    }
+
+    /// Runs the conversation until the caller is done and every reply has played out.
+    pub async fn run(
+        mut self,
+        mut requests: Streaming<ConverseRequest>,
+        mut voices: RecvVoiceReceiver,
+    ) -> Result<()> {
+        let mut uploading = true;
+
+        loop {
+            if let Some(status) = self.writer.take_failure() {
+                return Err(status.into());
+            }
+            if !self.finish_played().await {
+                return Ok(());
+            }
+            if !uploading && self.replies.is_empty() {
+                self.writer.finish();
+                return Ok(());
+            }
+
+            // Stop reading replies until playout catches up, so flow control pushes back on
+            // the caller, and wake up when the next reply has played out.
+            let queued = self.writer.queued();
+            let backlogged = uploading && queued >= self.buffer_frames;
+            let drained_below = self
+                .replies
+                .front()
+                .map(|(_, end)| self.pushed - end + 1)
+                .into_iter()
+                .chain(Some(self.buffer_frames).filter(|_| backlogged))
+                .min();
+
+            let wake = tokio::select! {
+                voice = voices.next() => Wake::Voice(voice),
+                request = requests.next(), if uploading && !backlogged => Wake::Request(request),
+                _ = self.writer.wait_below(drained_below.unwrap_or_default()),
+                    if drained_below.is_some() => Wake::Drained,
+            };
+
+            match wake {
+                Wake::Voice(Some(Ok(voice))) => {
+                    let talking = voice
+                        .talk_event
+                        .as_ref()
+                        .map(|event| event.kind() == TalkEventKind::Start)
+                        .unwrap_or(false);
+                    if talking && self.barge_in && !self.cut_off().await {
+                        return Ok(());
+                    }
+                    if !self.respond(converse_response::Message::Voice(voice)).await {
+                        return Ok(());
+                    }
+                }
+                Wake::Voice(Some(Err(status))) => return Err(status.into()),
+                Wake::Voice(None) => {
+                    return Err(Status::unavailable("voice subscription ended").into())
+                }
+                Wake::Request(Some(request)) => match request?.message {
+                    Some(converse_request::Message::Reply(reply)) => self.reply(reply)?,
+                    Some(converse_request::Message::Start(_)) => {
+                        return Err(Status::invalid_argument("conversation already started").into())
+                    }
+                    None => {}
+                },
+                Wake::Request(None) => {
+                    uploading = false;
+                    if self.input.is_some() {
+                        self.end_reply()?;
+                    }
+                }
+                Wake::Drained => {}
+            }
+        }
+    }
+
+    fn reply(&mut self, reply: ConverseReply) -> Result<()> {
+        if let Some(payload) = reply.payload.and_then(payload) {
+            if !self.discarding {
+                let input = match &mut self.input {
+                    Some(input) => input,
+                    None => self
+                        .input
+                        .insert(Input::new(&payload, reply.format.as_ref())?),
+                };
+                let frames = input.push(payload)?;
+                self.push(frames)?;
+            }
+        }
+
+        if reply.end {
+            self.end_reply()?;
+        }
+
+        Ok(())
+    }
+
+    /// Ends the reply being uploaded; one that was cut off has been reported already.
+    fn end_reply(&mut self) -> Result<()> {
+        if !self.discarding {
+            if let Some(mut input) = self.input.take() {
+                let frames = input.finish();
+                self.push(frames)?;
+            }
+            self.replies.push_back((self.next_reply, self.pushed));
+        }
+        self.discarding = false;
+        self.next_reply += 1;
+
+        Ok(())
+    }
+
+    /// Queues frames of the reply being uploaded, unless that would buffer too much.
+    fn push(&mut self, frames: Vec<Vec<i16>>) -> Result<()> {
+        if self.writer.queued() + frames.len() > self.max_frames {
+            return Err(Status::resource_exhausted(format!(
+                "more than {} frames of replies buffered",
+                self.max_frames
+            ))
+            .into());
+        }
+
+        self.pushed += frames.len();
+        self.writer.push(frames);
+        Ok(())
+    }
+
+    /// Reports the replies that have played out, returning false once the caller is gone.
+    async fn finish_played(&mut self) -> bool {
+        let played = self.pushed.saturating_sub(self.writer.queued());
+        while let Some(&(reply, end)) = self.replies.front() {
+            if end > played {
+                break;
+            }
+
+            self.replies.pop_front();
+            if !self.reply_event(reply, ReplyState::Finished).await {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Cuts off every reply that has not played out, returning false once the caller is
+    /// gone.
+    async fn cut_off(&mut self) -> bool {
+        if !self.finish_played().await {
+            return false;
+        }
+
+        let mut interrupted: Vec<u64> = self.replies.drain(..).map(|(reply, _)| reply).collect();
+        if self.input.take().is_some() {
+            interrupted.push(self.next_reply);
+            self.discarding = true;
+        }
+        if interrupted.is_empty() {
+            return true;
+        }
+
+        self.writer.clear();
+        self.pushed = 0;
+        for reply in interrupted {
+            if !self.reply_event(reply, ReplyState::Interrupted).await {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    async fn reply_event(&mut self, reply: u64, state: ReplyState) -> bool {
+        self.respond(converse_response::Message::Reply(ReplyEvent {
+            reply,
+            state: state as i32,
+        }))
+        .await
+    }
+
+    async fn respond(&mut self, message: converse_response::Message) -> bool {
+        let response = ConverseResponse {
+            message: Some(message),
+        };
+        self.sender.send(Ok(response)).await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::coder;
+    use crate::playout::{Address, Options, Resolved, Scheduler};
+
+    fn conversation(
+        scheduler: &mut Scheduler,
+        barge_in: bool,
+    ) -> (
+        Conversation,
+        mpsc::Receiver<Result<ConverseResponse, Status>>,
+    ) {
+        let (sender, receiver) = mpsc::channel(16);
+        let writer = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        let start = ConverseStart {
+            barge_in,
+            ..ConverseStart::default()
+        };
+
+        (Conversation::new(sender, writer, &start, 4, 100), receiver)
+    }
+
+    /// A reply message with `frames` whole frames of 22050 Hz mono PCM.
+    fn reply(frames: usize, end: bool) -> ConverseReply {
+        ConverseReply {
+            payload: Some(converse_reply::Payload::AudioData(vec![
+                0;
+                frames * coder::FRAME_SIZE
+                    * 2
+            ])),
+            end,
+            ..ConverseReply::default()
+        }
+    }
+
+    /// Plays out everything the scheduler has queued, moving `now` past it.
+    fn play_out(scheduler: &mut Scheduler, now: &mut Instant) {
+        let resolve = |_: &Address| {
+            Ok(Resolved {
+                client_index: 1,
+                real_player: false,
+            })
+        };
+        scheduler.tick(*now, resolve);
+        *now += Duration::from_secs(10);
+        scheduler.tick(*now, resolve);
+    }
+
+    fn events(
+        receiver: &mut mpsc::Receiver<Result<ConverseResponse, Status>>,
+    ) -> Vec<(u64, ReplyState)> {
+        let mut events = Vec::new();
+        while let Ok(Ok(response)) = receiver.try_recv() {
+            if let Some(converse_response::Message::Reply(event)) = response.message {
+                events.push((event.reply, event.state()));
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn barge_in_reports_played_replies_before_cutting_off_the_rest() {
+        let mut scheduler = Scheduler::new();
+        let (mut conversation, mut receiver) = conversation(&mut scheduler, true);
+        let mut now = Instant::now();
+
+        conversation.reply(reply(2, true)).unwrap();
+        play_out(&mut scheduler, &mut now);
+        conversation.reply(reply(2, true)).unwrap();
+        conversation.reply(reply(2, false)).unwrap();
+
+        // Reply 0 played out but was not reported yet when the player started talking.
+        assert_eq!(conversation.cut_off().now_or_never(), Some(true));
+        assert_eq!(
+            events(&mut receiver),
+            vec![
+                (0, ReplyState::Finished),
+                (1, ReplyState::Interrupted),
+                (2, ReplyState::Interrupted),
+            ]
+        );
+        assert_eq!(conversation.writer.queued(), 0);
+
+        // The end of the cut-off reply 2 queues nothing; reply 3 is counted from the cut.
+        conversation.reply(reply(0, true)).unwrap();
+        conversation.reply(reply(3, true)).unwrap();
+        assert_eq!(conversation.finish_played().now_or_never(), Some(true));
+        assert!(events(&mut receiver).is_empty());
+        play_out(&mut scheduler, &mut now);
+        assert_eq!(conversation.finish_played().now_or_never(), Some(true));
+        assert_eq!(events(&mut receiver), vec![(3, ReplyState::Finished)]);
+    }
+
+    #[test]
+    fn the_rest_of_a_reply_cut_off_midway_is_dropped() {
+        let mut scheduler = Scheduler::new();
+        let (mut conversation, mut receiver) = conversation(&mut scheduler, true);
+        let mut now = Instant::now();
+
+        conversation.reply(reply(2, false)).unwrap();
+        assert_eq!(conversation.cut_off().now_or_never(), Some(true));
+        assert_eq!(events(&mut receiver), vec![(0, ReplyState::Interrupted)]);
+
+        conversation.reply(reply(2, false)).unwrap();
+        conversation.reply(reply(1, true)).unwrap();
+        assert_eq!(conversation.writer.queued(), 0);
+        assert!(conversation.replies.is_empty());
+
+        conversation.reply(reply(1, true)).unwrap();
+        play_out(&mut scheduler, &mut now);
+        assert_eq!(conversation.finish_played().now_or_never(), Some(true));
+        assert_eq!(events(&mut receiver), vec![(1, ReplyState::Finished)]);
+    }
+
+    #[test]
+    fn replies_beyond_the_buffer_limit_are_refused() {
+        let mut scheduler = Scheduler::new();
+        let (mut conversation, _receiver) = conversation(&mut scheduler, false);
+
+        conversation.reply(reply(100, false)).unwrap();
+        let err = conversation.reply(reply(1, true)).unwrap_err();
+        assert_eq!(err.status().code(), tonic::Code::ResourceExhausted);
+    }
+}
@@ -0,0 +1,36 @@
+use std::fmt;
+
+use tonic::Status;
+
+/// Failure reported to a gRPC client or plugin.
+///
+/// `Status` is boxed so that results passed around the extension stay small; it converts
+/// back into a `Status` at the RPC boundary and prints its message across the FFI bridge.
+#[derive(Debug)]
+pub struct Error(Box<Status>);
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub fn status(&self) -> &Status {
+        &self.0
+    }
+}
+
+impl From<Status> for Error {
+    fn from(status: Status) -> Self {
+        Self(Box::new(status))
+    }
+}
+
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        *error.0
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.message())
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::SystemTime;
+
+use tokio::sync::mpsc;
+use tonic::Status;
+
+use crate::error::{Error, Result};
+use crate::ffi;
+use crate::recv;
+use crate::voiceserver::{GameEvent, GameEventKind, SubscribeEventsRequest};
+
+/// Events a subscription buffers for a slow reader; enough for a snapshot of a full server.
+const BUFFER_DEPTH: usize = 100;
+
+pub type GameEventReceiver = mpsc::Receiver<Result<GameEvent, Status>>;
+
+/// Event of `kind` happening now, with nothing else filled in.
+pub fn event(kind: GameEventKind) -> GameEvent {
+    GameEvent {
+        kind: kind as i32,
+        timestamp_us: recv::unix_micros(SystemTime::now()),
+        tick: ffi::get_game_tick(),
+        ..GameEvent::default()
+    }
+}
+
+/// Event of `kind` about `client`.
+pub fn player_event(kind: GameEventKind, client: &ffi::ClientInfo) -> GameEvent {
+    GameEvent {
+        player: Some(recv::speaker(client)),
+        ..event(kind)
+    }
+}
+
+/// A connect or bot creation event for a client entering the game.
+pub fn connect_event(client: &ffi::ClientInfo) -> GameEvent {
+    let kind = if client.fake_client {
+        GameEventKind::BotCreated
+    } else {
+        GameEventKind::PlayerConnect
+    };
+    player_event(kind, client)
+}
+
+struct Subscriber {
+    sender: mpsc::Sender<Result<GameEvent, Status>>,
+    /// Kinds the subscriber wants, every kind if empty.
+    kinds: Vec<GameEventKind>,
+    /// Whether the subscriber still waits for its snapshot, and gets nothing until then.
+    snapshot: bool,
+}
+
+impl Subscriber {
+    fn wants(&self, event: &GameEvent) -> bool {
+        !self.snapshot && (self.kinds.is_empty() || self.kinds.contains(&event.kind()))
+    }
+}
+
+/// `SubscribeEvents` streams, fed from the game's callbacks on the game thread.
+#[derive(Default)]
+pub struct Events {
+    subscribers: Vec<Subscriber>,
+    map: String,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&mut self, request: &SubscribeEventsRequest) -> Result<GameEventReceiver> {
+        let kinds = request
+            .kinds
+            .iter()
+            .map(|kind| {
+                GameEventKind::from_i32(*kind).ok_or_else(|| {
+                    Error::from(Status::invalid_argument(format!(
+                        "unknown event kind {}",
+                        kind
+                    )))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let (sender, receiver) = mpsc::channel(BUFFER_DEPTH);
+        self.subscribers.push(Subscriber {
+            sender,
+            kinds,
+            snapshot: request.snapshot,
+        });
+
+        Ok(receiver)
+    }
+
+    /// Sends `event` to every subscriber that wants it.
+    pub fn push(&mut self, event: GameEvent) {
+        self.subscribers
+            .retain(|subscriber| !subscriber.sender.is_closed());
+        for subscriber in self.subscribers.iter().filter(|s| s.wants(&event)) {
+            let _ = subscriber.sender.try_send(Ok(event.clone()));
+        }
+    }
+
+    pub fn map_start(&mut self, map: &str) {
+        self.map = map.to_owned();
+        self.push(GameEvent {
+            map: self.map.clone(),
+            ..event(GameEventKind::MapStart)
+        });
+    }
+
+    pub fn map_end(&mut self) {
+        let map = std::mem::take(&mut self.map);
+        self.push(GameEvent {
+            map,
+            ..event(GameEventKind::MapEnd)
+        });
+    }
+
+    /// Sends new subscribers that asked for one a snapshot of the map and the clients in
+    /// game, as reported by `client_info`.
+    pub fn tick<F>(&mut self, max_clients: i32, mut client_info: F)
+    where
+        F: FnMut(i32) -> ffi::ClientInfo,
+    {
+        if !self
+            .subscribers
+            .iter()
+            .any(|subscriber| subscriber.snapshot)
+        {
+            return;
+        }
+
+        let mut snapshot = Vec::new();
+        if !self.map.is_empty() {
+            snapshot.push(GameEvent {
+                map: self.map.clone(),
+                ..event(GameEventKind::MapStart)
+            });
+        }
+        snapshot.extend(
+            (0..max_clients)
+                .map(&mut client_info)
+                .filter(|client| client.in_game)
+                .map(|client| connect_event(&client)),
+        );
+
+        for subscriber in self.subscribers.iter_mut() {
+            if !subscriber.snapshot {
+                continue;
+            }
+
+            subscriber.snapshot = false;
+            for event in snapshot.iter().filter(|event| subscriber.wants(event)) {
+                let _ = subscriber.sender.try_send(Ok(event.clone()));
+            }
+        }
+    }
+}
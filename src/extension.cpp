@@ -1,6 +1,7 @@
 #include "extension.h"
 #include "extensions/ISDKTools.h"
 
+#include <cstring>
 #include <memory>
 #include <string>
 
@@ -8,22 +9,29 @@
 
 #include <iserver.h>
 #include <iclient.h>
+#include <iplayerinfo.h>
 #include <inetmessage.h>
+#include <ivoiceserver.h>
+#include <igameevents.h>
 #include <protobuf/netmessages.pb.h>
 
 #include <CDetour/detours.h>
 
-#define VOICESERVER_FAKECLIENT_NAME "Sympho"
-
 void* engineFactory = nullptr;
 
 ISDKTools *sdktools = nullptr;
 IServer *iserver = nullptr;
+IVoiceServer *g_pVoiceServer = nullptr;
+IGameEventManager2 *g_pGameEvents = nullptr;
 
 float *g_fClientVolumeMap = nullptr;
 
 CDetour *g_SV_BroadcastVoiceData_Detour = nullptr;
 
+IForward *g_pOnPlayFileProgress = nullptr;
+IForward *g_pOnPlayFileFinished = nullptr;
+IForward *g_pOnMusicNowPlaying = nullptr;
+
 #define MAXPLAYERS (64)
 
 static inline void *GetCGameClientFromIClient(IClient *iclient)
@@ -45,20 +53,19 @@ DETOUR_DECL_STATIC3(SV_BroadcastVoiceData, void, IClient*, cl, CCLCMsg_VoiceData
 		return;
 	}
 
-	auto player = playerhelpers->GetGamePlayer(client_index + 1);
-    if (player == nullptr || !player->IsConnected() || !player->IsInGame()) {
+	auto info = ext::get_client_info(client_index);
+    if (!info.in_game) {
     	DETOUR_STATIC_CALL(SV_BroadcastVoiceData)(cl, msg, unk);
 		return;
     }
 
-    auto steamid = player->GetSteamId64();
     auto volume = 1.0;
     if (g_fClientVolumeMap) {
     	volume = g_fClientVolumeMap[client_index];
     }
 
 	rust::Slice<const uint8_t> slice((const uint8_t*)msg.data().c_str(), msg.data().size());
-	auto data = ext::on_recv_voicedata(client_index, volume, steamid, slice);
+	auto data = ext::on_recv_voicedata(info, volume, slice);
 
 	msg.mutable_data()->assign((const char*)data.data(), data.size());
 
@@ -70,6 +77,52 @@ static void OnGameFrame(bool simulating) {
 	ext::on_gameframe();
 }
 
+class ClientListener : public IClientListener
+{
+public:
+	void OnClientPutInServer(int client) {
+		auto info = ext::get_client_info(client - 1);
+		if (info.in_game) {
+			ext::on_client_put_in_server(info);
+		}
+	}
+
+	void OnClientDisconnecting(int client) {
+		auto info = ext::get_client_info(client - 1);
+		if (info.in_game) {
+			ext::on_client_disconnecting(info);
+		}
+	}
+} g_ClientListener;
+
+class GameEventListener : public IGameEventListener2
+{
+public:
+	void FireGameEvent(IGameEvent *event) {
+		auto name = event->GetName();
+		if (strcmp(name, "player_team") == 0) {
+			// Players leaving fire this too, but they are reported as disconnecting.
+			if (event->GetBool("disconnect")) {
+				return;
+			}
+
+			auto client = playerhelpers->GetClientOfUserId(event->GetInt("userid"));
+			auto info = ext::get_client_info(client - 1);
+			if (info.in_game) {
+				ext::on_client_team(info, event->GetInt("team"), event->GetInt("oldteam"));
+			}
+		} else if (strcmp(name, "round_start") == 0) {
+			ext::on_round_start();
+		} else if (strcmp(name, "round_end") == 0) {
+			ext::on_round_end(event->GetInt("winner"), event->GetInt("reason"));
+		}
+	}
+
+	int GetEventDebugID() {
+		return EVENT_DEBUG_ID_INIT;
+	}
+} g_GameEventListener;
+
 extern const sp_nativeinfo_t g_Natives[];
 
 class Ext : public SDKExtension
@@ -81,6 +134,8 @@ public:
 			smutils->Format(error, maxlen, "Could not load engineFactory from metamod");
 			return false;
 		}
+		GET_V_IFACE_CURRENT(GetEngineFactory, g_pVoiceServer, IVoiceServer, INTERFACEVERSION_VOICESERVER);
+		GET_V_IFACE_CURRENT(GetEngineFactory, g_pGameEvents, IGameEventManager2, INTERFACEVERSION_GAMEEVENTSMANAGER2);
 		return true;
 	}
 
@@ -107,6 +162,15 @@ public:
 
 		smutils->AddGameFrameHook(&OnGameFrame);
 
+		playerhelpers->AddClientListener(&g_ClientListener);
+		g_pGameEvents->AddListener(&g_GameEventListener, "player_team", true);
+		g_pGameEvents->AddListener(&g_GameEventListener, "round_start", true);
+		g_pGameEvents->AddListener(&g_GameEventListener, "round_end", true);
+
+		g_pOnPlayFileProgress = forwards->CreateForward("VoiceServer_OnPlayFileProgress", ET_Ignore, 3, nullptr, Param_Cell, Param_Cell, Param_Cell);
+		g_pOnPlayFileFinished = forwards->CreateForward("VoiceServer_OnPlayFileFinished", ET_Ignore, 3, nullptr, Param_Cell, Param_Cell, Param_String);
+		g_pOnMusicNowPlaying = forwards->CreateForward("VoiceServer_OnMusicNowPlaying", ET_Ignore, 4, nullptr, Param_String, Param_Cell, Param_String, Param_Cell);
+
 		sharesys->AddNatives(myself, g_Natives);
 		sharesys->RegisterLibrary(myself, "VoiceServer");
 
@@ -123,7 +187,31 @@ public:
 
 		smutils->RemoveGameFrameHook(&OnGameFrame);
 
+		playerhelpers->RemoveClientListener(&g_ClientListener);
+		g_pGameEvents->RemoveListener(&g_GameEventListener);
+
 		ext::shutdown();
+
+		if (g_pOnPlayFileProgress) {
+			forwards->ReleaseForward(g_pOnPlayFileProgress);
+			g_pOnPlayFileProgress = nullptr;
+		}
+		if (g_pOnPlayFileFinished) {
+			forwards->ReleaseForward(g_pOnPlayFileFinished);
+			g_pOnPlayFileFinished = nullptr;
+		}
+		if (g_pOnMusicNowPlaying) {
+			forwards->ReleaseForward(g_pOnMusicNowPlaying);
+			g_pOnMusicNowPlaying = nullptr;
+		}
+	}
+
+	void OnCoreMapStart(edict_t *pEdictList, int edictCount, int clientMax) {
+		ext::on_map_start(STRING(gpGlobals->mapname));
+	}
+
+	void OnCoreMapEnd() {
+		ext::on_map_end();
 	}
 
 	void SDK_OnAllLoaded() {
@@ -147,9 +235,143 @@ static cell_t Native_ClientToVoiceVolumeMap(IPluginContext *pContext, const cell
 	return 0;
 }
 
+// Runs an extension call that may fail, turning its error into a native error.
+template <typename F>
+static cell_t CallExt(IPluginContext *pContext, F func)
+{
+	try {
+		return func();
+	} catch (const std::exception &err) {
+		return pContext->ThrowNativeError("%s", err.what());
+	}
+}
+
+static cell_t Native_PlayFile(IPluginContext *pContext, const cell_t *params)
+{
+	char *path;
+	pContext->LocalToString(params[2], &path);
+
+	// Clients are 1-based in SourcePawn; client 0 maps to slot -1, the extension's bot.
+	return CallExt(pContext, [&]() -> cell_t {
+		return static_cast<cell_t>(ext::play_file(params[1] - 1, path, params[3]));
+	});
+}
+
+static cell_t Native_MusicEnqueue(IPluginContext *pContext, const cell_t *params)
+{
+	char *bot, *path;
+	pContext->LocalToString(params[1], &bot);
+	pContext->LocalToString(params[2], &path);
+
+	return CallExt(pContext, [&]() -> cell_t {
+		return static_cast<cell_t>(ext::music_enqueue(bot, path));
+	});
+}
+
+static cell_t Native_MusicSkip(IPluginContext *pContext, const cell_t *params)
+{
+	char *bot;
+	pContext->LocalToString(params[1], &bot);
+
+	return CallExt(pContext, [&]() -> cell_t {
+		ext::music_skip(bot);
+		return 0;
+	});
+}
+
+static cell_t Native_MusicStop(IPluginContext *pContext, const cell_t *params)
+{
+	char *bot;
+	pContext->LocalToString(params[1], &bot);
+
+	return CallExt(pContext, [&]() -> cell_t {
+		ext::music_stop(bot);
+		return 0;
+	});
+}
+
+static cell_t Native_MusicPause(IPluginContext *pContext, const cell_t *params)
+{
+	char *bot;
+	pContext->LocalToString(params[1], &bot);
+
+	return CallExt(pContext, [&]() -> cell_t {
+		ext::music_set_paused(bot, true);
+		return 0;
+	});
+}
+
+static cell_t Native_MusicResume(IPluginContext *pContext, const cell_t *params)
+{
+	char *bot;
+	pContext->LocalToString(params[1], &bot);
+
+	return CallExt(pContext, [&]() -> cell_t {
+		ext::music_set_paused(bot, false);
+		return 0;
+	});
+}
+
+static cell_t Native_MusicSeek(IPluginContext *pContext, const cell_t *params)
+{
+	char *bot;
+	pContext->LocalToString(params[1], &bot);
+	if (params[2] < 0) {
+		return pContext->ThrowNativeError("Invalid position %d", params[2]);
+	}
+
+	return CallExt(pContext, [&]() -> cell_t {
+		ext::music_seek(bot, static_cast<uint32_t>(params[2]));
+		return 0;
+	});
+}
+
+static cell_t Native_MusicSetLoop(IPluginContext *pContext, const cell_t *params)
+{
+	char *bot;
+	pContext->LocalToString(params[1], &bot);
+
+	return CallExt(pContext, [&]() -> cell_t {
+		ext::music_set_loop(bot, params[2]);
+		return 0;
+	});
+}
+
+static cell_t Native_MusicShuffle(IPluginContext *pContext, const cell_t *params)
+{
+	char *bot;
+	pContext->LocalToString(params[1], &bot);
+
+	return CallExt(pContext, [&]() -> cell_t {
+		ext::music_shuffle(bot);
+		return 0;
+	});
+}
+
+static cell_t Native_MusicSetVolume(IPluginContext *pContext, const cell_t *params)
+{
+	char *bot;
+	pContext->LocalToString(params[1], &bot);
+
+	return CallExt(pContext, [&]() -> cell_t {
+		ext::music_set_volume(bot, sp_ctof(params[2]));
+		return 0;
+	});
+}
+
 const sp_nativeinfo_t g_Natives[] = 
 {
 	{ "ClientToVoiceVolumeMap", Native_ClientToVoiceVolumeMap },
+	{ "VoiceServer_PlayFile", Native_PlayFile },
+	{ "VoiceServer_MusicEnqueue", Native_MusicEnqueue },
+	{ "VoiceServer_MusicSkip", Native_MusicSkip },
+	{ "VoiceServer_MusicStop", Native_MusicStop },
+	{ "VoiceServer_MusicPause", Native_MusicPause },
+	{ "VoiceServer_MusicResume", Native_MusicResume },
+	{ "VoiceServer_MusicSeek", Native_MusicSeek },
+	{ "VoiceServer_MusicSetLoop", Native_MusicSetLoop },
+	{ "VoiceServer_MusicShuffle", Native_MusicShuffle },
+	{ "VoiceServer_MusicSetVolume", Native_MusicSetVolume },
 	{ nullptr, nullptr },
 };
 
@@ -158,29 +380,10 @@ namespace ext {
 		if (iserver == nullptr) {
 			return;
 		}
-		if (client_index < -1 || client_index >= MAXPLAYERS) {
+		if (client_index < 0 || client_index >= MAXPLAYERS) {
 			return;
 		}
 
-		if (client_index == -1) {
-			static int bot_index = -1;
-			if (bot_index != -1) {
-				auto player = playerhelpers->GetGamePlayer(bot_index + 1);
-				if (player == nullptr || !player->IsConnected() || !player->IsInGame() || !player->IsFakeClient()) {
-					bot_index = -1;
-				}
-			}
-			if (bot_index == -1) {
-				auto edict = engine->CreateFakeClient(VOICESERVER_FAKECLIENT_NAME);
-				if (edict == nullptr) {
-					return;
-				}
-				auto player = playerhelpers->GetGamePlayer(edict);
-				bot_index = player->GetIndex() - 1;
-			}
-			client_index = bot_index;
-		}
-
         auto player = playerhelpers->GetGamePlayer(client_index + 1);
         if (player == nullptr || !player->IsConnected() || !player->IsInGame()) {
         	return;
@@ -199,6 +402,148 @@ namespace ext {
         DETOUR_STATIC_CALL(SV_BroadcastVoiceData)(cl, msg, false);
 	}
 
+	int32_t find_client_by_steamid(uint64_t steamid) {
+		for (int i = 1; i <= playerhelpers->GetMaxClients(); i++) {
+			auto player = playerhelpers->GetGamePlayer(i);
+			if (player == nullptr || !player->IsConnected() || !player->IsInGame()) {
+				continue;
+			}
+			if (player->GetSteamId64() == steamid) {
+				return i - 1;
+			}
+		}
+
+		return -1;
+	}
+
+	ClientInfo get_client_info(int32_t client_index) {
+		ClientInfo info{};
+		info.client_index = client_index;
+		if (client_index < 0 || client_index >= MAXPLAYERS) {
+			return info;
+		}
+
+		auto player = playerhelpers->GetGamePlayer(client_index + 1);
+		if (player == nullptr || !player->IsConnected() || !player->IsInGame()) {
+			return info;
+		}
+
+		info.in_game = true;
+		info.userid = player->GetUserId();
+		info.steamid = player->GetSteamId64();
+		// Names are not guaranteed to be valid UTF-8, so they cross the bridge as bytes.
+		for (auto name = player->GetName(); *name != '\0'; name++) {
+			info.name.push_back(static_cast<uint8_t>(*name));
+		}
+		auto playerinfo = player->GetPlayerInfo();
+		if (playerinfo != nullptr) {
+			info.team = playerinfo->GetTeamIndex();
+			info.alive = !playerinfo->IsDead();
+		}
+		// SourceTV and replay bots relay real players, so they don't count as fake voices.
+		info.fake_client = player->IsFakeClient() && !player->IsSourceTV() && !player->IsReplay();
+
+		return info;
+	}
+
+	int32_t create_fake_client(rust::Str name) {
+		std::string name_str(name.data(), name.size());
+		auto edict = engine->CreateFakeClient(name_str.c_str());
+		if (edict == nullptr) {
+			return -1;
+		}
+
+		auto player = playerhelpers->GetGamePlayer(edict);
+		if (player == nullptr) {
+			return -1;
+		}
+
+		return player->GetIndex() - 1;
+	}
+
+	void kick_client(int32_t userid, rust::Str reason) {
+		std::string reason_str(reason.data(), reason.size());
+		char cmd[256];
+		smutils->Format(cmd, sizeof(cmd), "kickid %d \"%s\"\n", userid, reason_str.c_str());
+		engine->ServerCommand(cmd);
+	}
+
+	void change_client_team(int32_t client_index, int32_t team) {
+		auto player = playerhelpers->GetGamePlayer(client_index + 1);
+		if (player == nullptr || !player->IsConnected() || !player->IsInGame()) {
+			return;
+		}
+
+		auto playerinfo = player->GetPlayerInfo();
+		if (playerinfo == nullptr) {
+			return;
+		}
+
+		playerinfo->ChangeTeam(team);
+	}
+
+	int32_t get_game_tick() {
+		return gpGlobals->tickcount;
+	}
+
+	rust::String get_config_value(rust::Str key) {
+		std::string key_str(key.data(), key.size());
+		auto value = smutils->GetCoreConfigValue(key_str.c_str());
+		if (value == nullptr) {
+			return rust::String();
+		}
+
+		return rust::String(value);
+	}
+
+	bool client_listening(int32_t receiver, int32_t sender) {
+		if (g_pVoiceServer == nullptr) {
+			return false;
+		}
+		if (receiver < 0 || receiver >= MAXPLAYERS || sender < 0 || sender >= MAXPLAYERS) {
+			return false;
+		}
+
+		return g_pVoiceServer->GetClientListening(receiver + 1, sender + 1);
+	}
+
+	void forward_file_progress(uint64_t id, uint32_t position_ms, uint32_t duration_ms) {
+		if (g_pOnPlayFileProgress == nullptr) {
+			return;
+		}
+
+		g_pOnPlayFileProgress->PushCell(static_cast<cell_t>(id));
+		g_pOnPlayFileProgress->PushCell(static_cast<cell_t>(position_ms));
+		g_pOnPlayFileProgress->PushCell(static_cast<cell_t>(duration_ms));
+		g_pOnPlayFileProgress->Execute(nullptr);
+	}
+
+	void forward_file_finished(uint64_t id, rust::Str error) {
+		if (g_pOnPlayFileFinished == nullptr) {
+			return;
+		}
+
+		std::string error_str(error.data(), error.size());
+		g_pOnPlayFileFinished->PushCell(static_cast<cell_t>(id));
+		g_pOnPlayFileFinished->PushCell(error_str.empty());
+		g_pOnPlayFileFinished->PushString(error_str.c_str());
+		g_pOnPlayFileFinished->Execute(nullptr);
+	}
+
+	void forward_music_now_playing(rust::Str bot, uint64_t track_id, rust::Str title, uint32_t duration_ms) {
+		if (g_pOnMusicNowPlaying == nullptr) {
+			return;
+		}
+
+		std::string bot_str(bot.data(), bot.size());
+		std::string title_str(title.data(), title.size());
+		g_pOnMusicNowPlaying->PushString(bot_str.c_str());
+		g_pOnMusicNowPlaying->PushCell(static_cast<cell_t>(track_id));
+		g_pOnMusicNowPlaying->PushString(title_str.c_str());
+		g_pOnMusicNowPlaying->PushCell(static_cast<cell_t>(duration_ms));
+		g_pOnMusicNowPlaying->Execute(nullptr);
+	}
+
 	void log_error(rust::Str msg) {
 		std::string msg_str(msg.data(), msg.size());
 		smutils->LogError(myself, "%s", msg_str.c_str());
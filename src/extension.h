@@ -7,8 +7,32 @@
 
 namespace ext {
 
+struct ClientInfo;
+
 void send_client_voice(int32_t client_index, rust::Slice<const uint8_t> audio_data);
 
+int32_t find_client_by_steamid(uint64_t steamid);
+
+ClientInfo get_client_info(int32_t client_index);
+
+int32_t create_fake_client(rust::Str name);
+
+void kick_client(int32_t userid, rust::Str reason);
+
+void change_client_team(int32_t client_index, int32_t team);
+
+int32_t get_game_tick();
+
+rust::String get_config_value(rust::Str key);
+
+bool client_listening(int32_t receiver, int32_t sender);
+
+void forward_file_progress(uint64_t id, uint32_t position_ms, uint32_t duration_ms);
+
+void forward_file_finished(uint64_t id, rust::Str error);
+
+void forward_music_now_playing(rust::Str bot, uint64_t track_id, rust::Str title, uint32_t duration_ms);
+
 void log_error(rust::Str msg);
 
 }
@@ -1,9 +1,11 @@
-use std::collections::VecDeque;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Mutex;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use tokio::runtime::{Builder, Runtime};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
 
@@ -14,14 +16,48 @@ static mut RUNTIME: Option<Runtime> = None;
 static mut RUNTIME_GUARD: Option<tokio::runtime::EnterGuard<'static>> = None;
 
 const MAXPLAYERS: usize = 64;
+/// Decoded chunks an `EnqueueStream` upload may queue ahead of its music bot.
+const MUSIC_STREAM_CHUNKS: usize = 16;
 
+mod bots;
 mod coder;
+mod config;
+mod converse;
+mod error;
+mod events;
+mod file;
+mod input;
+mod mixdown;
+mod mixer;
+mod music;
+mod ogg;
+mod playout;
+mod policy;
+mod recv;
+mod resample;
+mod timeline;
+mod utterance;
 
-type VoiceSenderVec = Vec<mpsc::Sender<Result<RecvVoiceResponse, Status>>>;
+type VoiceSenderVec = Vec<recv::Subscriber>;
+
+/// Next voice packet sequence number of the client occupying a slot.
+#[derive(Clone, Default)]
+struct Sequence {
+    userid: i32,
+    next: u64,
+}
 
 lazy_static::lazy_static! {
-    static ref SENDVOICEPKTS: Mutex<VecDeque<(i32, Vec<u8>)>> = Mutex::new(VecDeque::new());
+    static ref CONFIG: RwLock<config::Config> = RwLock::new(config::Config::default());
+    static ref PLAYOUT: Mutex<playout::Scheduler> = Mutex::new(playout::Scheduler::new());
+    static ref BOTS: Mutex<bots::Registry> = Mutex::new(bots::Registry::new());
     static ref VOICESENDERS: Mutex<VoiceSenderVec> = Mutex::new(Vec::new());
+    static ref SEQUENCES: Mutex<Vec<Sequence>> = Mutex::new(vec![Sequence::default(); MAXPLAYERS]);
+    static ref FILE_EVENTS: Mutex<Vec<FileEvent>> = Mutex::new(Vec::new());
+    static ref MUSIC: Mutex<music::Library> = Mutex::new(music::Library::new());
+    static ref TIMELINES: Mutex<timeline::Timelines> = Mutex::new(timeline::Timelines::new());
+    static ref MIXDOWN: Mutex<mixdown::Mixdown> = Mutex::new(mixdown::Mixdown::new());
+    static ref EVENTS: Mutex<events::Events> = Mutex::new(events::Events::new());
     static ref DECODERS: Vec<Mutex<coder::Decoder>> = {
         let mut vec = Vec::new();
         for _ in 0..MAXPLAYERS {
@@ -40,12 +76,111 @@ lazy_static::lazy_static! {
     };
 }
 
+use voiceserver::music_service_server::{MusicService, MusicServiceServer};
 use voiceserver::voice_service_server::{VoiceService, VoiceServiceServer};
-use voiceserver::{RecvVoiceRequest, RecvVoiceResponse, SendVoiceRequest, SendVoiceResponse};
+use voiceserver::{
+    converse_request, enqueue_stream_request, injection, play_file_request, send_voice_request,
+    Bot, ConverseRequest, ConverseResponse, EnqueueRequest, EnqueueResponse, EnqueueStreamRequest,
+    FrameKind, GameEvent, GameEventKind, GetQueueRequest, GetQueueResponse, Injection,
+    InjectionControlRequest, InjectionControlResponse, InjectionState, ListBotsRequest,
+    ListBotsResponse, ListInjectionsRequest, ListInjectionsResponse, LoopMode, MusicControlRequest,
+    MusicControlResponse, PlayFileRequest, PlayFileResponse, Preemption, RecvMixedVoiceRequest,
+    RecvMixedVoiceResponse, RecvVoiceRequest, RecvVoiceResponse, SeekRequest, SendVoiceRequest,
+    SendVoiceResponse, SetLoopRequest, SetVolumeRequest, SubscribeEventsRequest, Track,
+};
 pub mod voiceserver {
     tonic::include_proto!("voiceserver");
 }
 
+fn injection_address(target: Option<&send_voice_request::Target>) -> playout::Address {
+    match target {
+        Some(send_voice_request::Target::ClientIndex(client_index)) => {
+            playout::Address::Slot(*client_index)
+        }
+        Some(send_voice_request::Target::Steamid(steamid)) => playout::Address::SteamId(*steamid),
+        Some(send_voice_request::Target::BotName(name)) if !name.is_empty() => {
+            playout::Address::Bot(name.clone())
+        }
+        Some(send_voice_request::Target::BotName(_)) => {
+            playout::Address::Bot(bots::DEFAULT_NAME.to_string())
+        }
+        None => playout::Address::Slot(0),
+    }
+}
+
+fn file_address(target: Option<&play_file_request::Target>) -> playout::Address {
+    match target {
+        Some(play_file_request::Target::ClientIndex(client_index)) => {
+            playout::Address::Slot(*client_index)
+        }
+        Some(play_file_request::Target::Steamid(steamid)) => playout::Address::SteamId(*steamid),
+        Some(play_file_request::Target::BotName(name)) if !name.is_empty() => {
+            playout::Address::Bot(name.clone())
+        }
+        Some(play_file_request::Target::BotName(_)) => {
+            playout::Address::Bot(bots::DEFAULT_NAME.to_string())
+        }
+        None => playout::Address::Slot(0),
+    }
+}
+
+fn preemption(preemption: Preemption) -> playout::Preemption {
+    match preemption {
+        Preemption::Duck => playout::Preemption::Duck,
+        Preemption::Pause => playout::Preemption::Pause,
+        Preemption::Cancel => playout::Preemption::Cancel,
+    }
+}
+
+fn buffered_too_much(config: &config::Config) -> Status {
+    Status::resource_exhausted(format!(
+        "more than {} ms of audio buffered",
+        config.max_buffered.as_millis()
+    ))
+}
+
+fn music_bot(name: String) -> String {
+    if name.is_empty() {
+        bots::DEFAULT_NAME.to_string()
+    } else {
+        name
+    }
+}
+
+fn loop_mode(mode: i32) -> error::Result<music::Loop> {
+    match LoopMode::from_i32(mode) {
+        Some(LoopMode::Off) => Ok(music::Loop::Off),
+        Some(LoopMode::Track) => Ok(music::Loop::Track),
+        Some(LoopMode::Queue) => Ok(music::Loop::Queue),
+        None => Err(Status::invalid_argument(format!("unknown loop mode {}", mode)).into()),
+    }
+}
+
+fn track(info: music::TrackInfo) -> Track {
+    Track {
+        id: info.id,
+        title: info.title,
+        duration_ms: info
+            .duration
+            .map(|duration| duration.as_millis() as u32)
+            .unwrap_or_default(),
+        streamed: info.streamed,
+    }
+}
+
+/// Progress of a file started by a plugin, handed to the game thread for its forwards.
+enum FileEvent {
+    Progress {
+        id: u64,
+        position: Duration,
+        duration: Option<Duration>,
+    },
+    Finished {
+        id: u64,
+        error: Option<error::Error>,
+    },
+}
+
 #[derive(Default)]
 pub struct VoiceServiceImpl {}
 
@@ -55,68 +190,564 @@ impl VoiceService for VoiceServiceImpl {
         &self,
         request: Request<tonic::Streaming<SendVoiceRequest>>,
     ) -> Result<Response<SendVoiceResponse>, Status> {
+        let config = CONFIG.read().unwrap().clone();
+        let origin = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        let impersonate = policy::may_impersonate(&config, request.metadata());
         let mut stream = request.into_inner();
-        let mut encoder = coder::Encoder::new();
+        let mut input = input::Input::default();
+        let buffer_frames = playout::frames_for(config.stream_buffer).max(1);
+        let max_frames = playout::frames_for(config.max_buffered).max(buffer_frames);
+        let mut writer: Option<playout::StreamWriter> = None;
 
         while let Some(req) = stream.next().await {
-            let req = req?;
-            if req.audio_data.is_empty() {
+            let mut req = req?;
+            let payload = match req.payload.take() {
+                Some(send_voice_request::Payload::AudioData(data)) if data.is_empty() => continue,
+                Some(send_voice_request::Payload::OpusPacket(packet)) if packet.is_empty() => {
+                    continue
+                }
+                Some(send_voice_request::Payload::OggOpus(data)) if data.is_empty() => continue,
+                Some(payload) => payload,
+                None => continue,
+            };
+
+            let writer = match &writer {
+                Some(writer) => writer,
+                None => {
+                    input = input::Input::new(&payload, req.format.as_ref())?;
+                    let address = injection_address(req.target.as_ref());
+                    let options = playout::Options {
+                        priority: req.priority,
+                        preemption: preemption(req.preemption()),
+                        impersonate,
+                    };
+                    let opened = PLAYOUT
+                        .lock()
+                        .unwrap()
+                        .open(address, origin.clone(), options);
+                    writer.get_or_insert(opened)
+                }
+            };
+
+            let frames = input.push(payload)?;
+            if frames.is_empty() {
                 continue;
             }
 
-            let mut input = Vec::new();
-            for chunk in req.audio_data.as_slice().chunks(2) {
-                let mut v: [u8; 2] = Default::default();
-                v.copy_from_slice(chunk);
-                input.push(i16::from_le_bytes(v));
+            if writer.queued() + frames.len() > max_frames {
+                return Err(buffered_too_much(&config));
             }
+            writer.push(frames);
 
-            let mut frames = input.len() / 512;
-            if input.len() % 512 != 0 {
-                frames += 1;
-                input.resize(frames * 512, 0);
-            }
+            // Stop reading until playout catches up, so flow control pushes back on the sender.
+            writer.wait_below(buffer_frames).await;
 
-            let mut data = vec![0; frames * 64];
-            let data_iter = data.as_mut_slice().chunks_mut(64);
-            for (input, data) in input.as_slice().chunks(512).zip(data_iter) {
-                match encoder.encode(input, data) {
-                    Ok(_) => {}
-                    Err(err) => {
-                        ffi::log_error(&format!("encode error: {}", err));
-                        continue;
-                    }
-                }
+            if let Some(status) = writer.take_failure() {
+                return Err(status);
             }
+        }
+
+        let writer = match writer {
+            Some(writer) => writer,
+            None => return Ok(Response::new(SendVoiceResponse::default())),
+        };
+        let frames = input.finish();
+        if writer.queued() + frames.len() > max_frames {
+            return Err(buffered_too_much(&config));
+        }
+        writer.push(frames);
 
-            let mut pending = SENDVOICEPKTS.lock().unwrap();
-            pending.push_back((req.client_index, data));
+        // A short upload can end before the game thread looked at its target; wait for
+        // that, so a target that is missing or off limits fails the call.
+        writer.wait_resolved().await;
+        if let Some(status) = writer.take_failure() {
+            return Err(status);
         }
+        let injection_id = writer.id();
+        writer.finish();
 
-        Ok(Response::new(SendVoiceResponse::default()))
+        Ok(Response::new(SendVoiceResponse { injection_id }))
     }
 
-    type RecvVoiceDataStream = ReceiverStream<Result<RecvVoiceResponse, Status>>;
+    type RecvVoiceDataStream = recv::RecvVoiceReceiver;
 
     async fn recv_voice_data(
         &self,
-        _request: Request<RecvVoiceRequest>,
+        request: Request<RecvVoiceRequest>,
     ) -> Result<Response<Self::RecvVoiceDataStream>, Status> {
-        let (tx, rx) = mpsc::channel(10);
+        let (subscriber, rx) = recv::Subscriber::new(request.get_ref())?;
 
         let mut senders = VOICESENDERS.lock().unwrap();
-        senders.push(tx);
+        senders.push(subscriber);
+
+        Ok(Response::new(rx))
+    }
+
+    type RecvMixedVoiceStream = ReceiverStream<Result<RecvMixedVoiceResponse, Status>>;
+
+    async fn recv_mixed_voice(
+        &self,
+        request: Request<RecvMixedVoiceRequest>,
+    ) -> Result<Response<Self::RecvMixedVoiceStream>, Status> {
+        // A mix frame goes out every 23 ms, so allow for about a second of backlog.
+        let (tx, rx) = mpsc::channel(50);
+        let subscriber = mixdown::Subscriber::new(tx, request.get_ref())?;
+        MIXDOWN.lock().unwrap().subscribe(subscriber);
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type SubscribeEventsStream = ReceiverStream<Result<GameEvent, Status>>;
+
+    async fn subscribe_events(
+        &self,
+        request: Request<SubscribeEventsRequest>,
+    ) -> Result<Response<Self::SubscribeEventsStream>, Status> {
+        let rx = EVENTS.lock().unwrap().subscribe(request.get_ref())?;
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ConverseStream = ReceiverStream<Result<ConverseResponse, Status>>;
+
+    async fn converse(
+        &self,
+        request: Request<tonic::Streaming<ConverseRequest>>,
+    ) -> Result<Response<Self::ConverseStream>, Status> {
+        let config = CONFIG.read().unwrap().clone();
+        let origin = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        let impersonate = policy::may_impersonate(&config, request.metadata());
+        let mut stream = request.into_inner();
+
+        let start = match stream.next().await.transpose()?.and_then(|req| req.message) {
+            Some(converse_request::Message::Start(start)) => start,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "a conversation must begin with its start",
+                ))
+            }
+        };
+        let (subscriber, voices) = recv::Subscriber::new(&converse::voice_request(&start)?)?;
+
+        let options = playout::Options {
+            priority: start.priority,
+            preemption: preemption(start.preemption()),
+            impersonate,
+        };
+        let address = playout::Address::Bot(converse::bot_name(&start));
+        let writer = PLAYOUT.lock().unwrap().open(address, origin, options);
+        VOICESENDERS.lock().unwrap().push(subscriber);
+
+        let buffer_frames = playout::frames_for(config.stream_buffer).max(1);
+        let max_frames = playout::frames_for(config.max_buffered).max(buffer_frames);
+        // Voice packets arrive about every 23 ms while the player talks.
+        let (tx, rx) = mpsc::channel(50);
+        let conversation =
+            converse::Conversation::new(tx.clone(), writer, &start, buffer_frames, max_frames);
+        tokio::spawn(async move {
+            if let Err(err) = conversation.run(stream, voices).await {
+                let _ = tx.send(Err(err.into())).await;
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn list_injections(
+        &self,
+        _request: Request<ListInjectionsRequest>,
+    ) -> Result<Response<ListInjectionsResponse>, Status> {
+        let injections = PLAYOUT
+            .lock()
+            .unwrap()
+            .list()
+            .into_iter()
+            .map(|info| {
+                let state = match info.state {
+                    playout::State::Waiting => InjectionState::Waiting,
+                    playout::State::Playing => InjectionState::Playing,
+                    playout::State::Paused => InjectionState::Paused,
+                };
+
+                let target = match info.address {
+                    playout::Address::Slot(client_index) => {
+                        injection::Target::ClientIndex(client_index)
+                    }
+                    playout::Address::SteamId(steamid) => injection::Target::Steamid(steamid),
+                    playout::Address::Bot(name) => injection::Target::BotName(name),
+                };
+
+                Injection {
+                    id: info.id,
+                    target: Some(target),
+                    origin: info.origin,
+                    priority: info.options.priority,
+                    state: state as i32,
+                    queued_ms: info.queued.as_millis() as u32,
+                    played_ms: info.played.as_millis() as u32,
+                }
+            })
+            .collect();
+
+        Ok(Response::new(ListInjectionsResponse { injections }))
+    }
+
+    async fn cancel_injection(
+        &self,
+        request: Request<InjectionControlRequest>,
+    ) -> Result<Response<InjectionControlResponse>, Status> {
+        let id = request.into_inner().id;
+        if !PLAYOUT.lock().unwrap().cancel(id) {
+            return Err(Status::not_found(format!("no injection with id {}", id)));
+        }
+
+        Ok(Response::new(InjectionControlResponse::default()))
+    }
+
+    async fn pause_injection(
+        &self,
+        request: Request<InjectionControlRequest>,
+    ) -> Result<Response<InjectionControlResponse>, Status> {
+        let id = request.into_inner().id;
+        if !PLAYOUT.lock().unwrap().set_paused(id, true) {
+            return Err(Status::not_found(format!("no injection with id {}", id)));
+        }
+
+        Ok(Response::new(InjectionControlResponse::default()))
+    }
+
+    async fn resume_injection(
+        &self,
+        request: Request<InjectionControlRequest>,
+    ) -> Result<Response<InjectionControlResponse>, Status> {
+        let id = request.into_inner().id;
+        if !PLAYOUT.lock().unwrap().set_paused(id, false) {
+            return Err(Status::not_found(format!("no injection with id {}", id)));
+        }
+
+        Ok(Response::new(InjectionControlResponse::default()))
+    }
+
+    async fn list_bots(
+        &self,
+        _request: Request<ListBotsRequest>,
+    ) -> Result<Response<ListBotsResponse>, Status> {
+        let bots = BOTS
+            .lock()
+            .unwrap()
+            .list(Instant::now())
+            .into_iter()
+            .map(|info| Bot {
+                name: info.name,
+                in_game: info.client_index.is_some(),
+                client_index: info.client_index.unwrap_or(-1),
+                team: info.team,
+                idle_ms: info.idle.as_millis() as u32,
+            })
+            .collect();
+
+        Ok(Response::new(ListBotsResponse { bots }))
+    }
+
+    type PlayFileStream = ReceiverStream<Result<PlayFileResponse, Status>>;
+
+    async fn play_file(
+        &self,
+        request: Request<PlayFileRequest>,
+    ) -> Result<Response<Self::PlayFileStream>, Status> {
+        let config = CONFIG.read().unwrap().clone();
+        let origin = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        let impersonate = policy::may_impersonate(&config, request.metadata());
+        let request = request.into_inner();
+
+        let path = file::resolve(&config.file_root, &request.path)?;
+        let decoder = tokio::task::block_in_place(|| file::Decoder::open(&path))?;
+        let duration_ms = decoder
+            .duration()
+            .map(|duration| duration.as_millis() as u32)
+            .unwrap_or_default();
+
+        let options = playout::Options {
+            priority: request.priority,
+            preemption: preemption(request.preemption()),
+            impersonate,
+        };
+        let writer =
+            PLAYOUT
+                .lock()
+                .unwrap()
+                .open(file_address(request.target.as_ref()), origin, options);
+        let injection_id = writer.id();
+        let buffer_frames = playout::frames_for(config.stream_buffer).max(1);
+
+        let (tx, rx) = mpsc::channel(4);
+        let response = move |position: Duration, finished| PlayFileResponse {
+            injection_id,
+            position_ms: position.as_millis() as u32,
+            duration_ms,
+            finished,
+        };
+        let _ = tx.try_send(Ok(response(Duration::ZERO, false)));
+
+        tokio::spawn(async move {
+            let result = file::play(decoder, writer, buffer_frames, |position| {
+                let _ = tx.try_send(Ok(response(position, false)));
+                !tx.is_closed()
+            })
+            .await;
+            let _ = tx
+                .send(
+                    result
+                        .map(|position| response(position, true))
+                        .map_err(Status::from),
+                )
+                .await;
+        });
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 }
 
+#[derive(Default)]
+pub struct MusicServiceImpl {}
+
+#[tonic::async_trait]
+impl MusicService for MusicServiceImpl {
+    async fn enqueue(
+        &self,
+        request: Request<EnqueueRequest>,
+    ) -> Result<Response<EnqueueResponse>, Status> {
+        let file_root = CONFIG.read().unwrap().file_root.clone();
+        let request = request.into_inner();
+
+        let path = file::resolve(&file_root, &request.path)?;
+        let decoder = tokio::task::block_in_place(|| file::Decoder::open(&path))?;
+        let title = if request.title.is_empty() {
+            request.path
+        } else {
+            request.title
+        };
+
+        let track_id = MUSIC.lock().unwrap().enqueue(
+            &music_bot(request.bot_name),
+            title,
+            decoder.duration(),
+            music::Source::File(path),
+        );
+
+        Ok(Response::new(EnqueueResponse { track_id }))
+    }
+
+    async fn enqueue_stream(
+        &self,
+        request: Request<tonic::Streaming<EnqueueStreamRequest>>,
+    ) -> Result<Response<EnqueueResponse>, Status> {
+        let mut stream = request.into_inner();
+        let mut input = input::Input::default();
+        let mut upload: Option<(String, u64, mpsc::Sender<Vec<Vec<i16>>>)> = None;
+
+        let result = async {
+            while let Some(req) = stream.next().await {
+                let req = req?;
+                let payload = match req.payload {
+                    Some(enqueue_stream_request::Payload::AudioData(data)) if !data.is_empty() => {
+                        send_voice_request::Payload::AudioData(data)
+                    }
+                    Some(enqueue_stream_request::Payload::OpusPacket(packet))
+                        if !packet.is_empty() =>
+                    {
+                        send_voice_request::Payload::OpusPacket(packet)
+                    }
+                    Some(enqueue_stream_request::Payload::OggOpus(data)) if !data.is_empty() => {
+                        send_voice_request::Payload::OggOpus(data)
+                    }
+                    _ => continue,
+                };
+
+                let (_, _, tx) = match &upload {
+                    Some(upload) => upload,
+                    None => {
+                        input = input::Input::new(&payload, req.format.as_ref())?;
+                        let bot = music_bot(req.bot_name);
+                        let (tx, rx) = mpsc::channel(MUSIC_STREAM_CHUNKS);
+                        let track_id = MUSIC.lock().unwrap().enqueue(
+                            &bot,
+                            req.title,
+                            None,
+                            music::Source::Stream(rx),
+                        );
+                        upload.get_or_insert((bot, track_id, tx))
+                    }
+                };
+
+                let frames = input.push(payload)?;
+                if frames.is_empty() {
+                    continue;
+                }
+                if tx.send(frames).await.is_err() {
+                    return Err(Status::cancelled("track was skipped or stopped"));
+                }
+            }
+
+            let (_, track_id, tx) = match &upload {
+                Some(upload) => upload,
+                None => return Err(Status::invalid_argument("stream carried no audio")),
+            };
+            let frames = input.finish();
+            if !frames.is_empty() && tx.send(frames).await.is_err() {
+                return Err(Status::cancelled("track was skipped or stopped"));
+            }
+            Ok(*track_id)
+        }
+        .await;
+
+        match result {
+            Ok(track_id) => Ok(Response::new(EnqueueResponse { track_id })),
+            Err(status) => {
+                // A partial upload is not worth playing, whether it is queued or on air.
+                if let Some((bot, track_id, _)) = upload {
+                    MUSIC.lock().unwrap().remove(&bot, track_id);
+                }
+                Err(status)
+            }
+        }
+    }
+
+    async fn skip(
+        &self,
+        request: Request<MusicControlRequest>,
+    ) -> Result<Response<MusicControlResponse>, Status> {
+        let bot = music_bot(request.into_inner().bot_name);
+        MUSIC.lock().unwrap().skip(&bot)?;
+
+        Ok(Response::new(MusicControlResponse::default()))
+    }
+
+    async fn stop(
+        &self,
+        request: Request<MusicControlRequest>,
+    ) -> Result<Response<MusicControlResponse>, Status> {
+        let bot = music_bot(request.into_inner().bot_name);
+        MUSIC.lock().unwrap().stop(&bot)?;
+
+        Ok(Response::new(MusicControlResponse::default()))
+    }
+
+    async fn pause(
+        &self,
+        request: Request<MusicControlRequest>,
+    ) -> Result<Response<MusicControlResponse>, Status> {
+        let bot = music_bot(request.into_inner().bot_name);
+        MUSIC.lock().unwrap().set_paused(&bot, true)?;
+
+        Ok(Response::new(MusicControlResponse::default()))
+    }
+
+    async fn resume(
+        &self,
+        request: Request<MusicControlRequest>,
+    ) -> Result<Response<MusicControlResponse>, Status> {
+        let bot = music_bot(request.into_inner().bot_name);
+        MUSIC.lock().unwrap().set_paused(&bot, false)?;
+
+        Ok(Response::new(MusicControlResponse::default()))
+    }
+
+    async fn shuffle(
+        &self,
+        request: Request<MusicControlRequest>,
+    ) -> Result<Response<MusicControlResponse>, Status> {
+        let bot = music_bot(request.into_inner().bot_name);
+        MUSIC.lock().unwrap().shuffle(&bot)?;
+
+        Ok(Response::new(MusicControlResponse::default()))
+    }
+
+    async fn seek(
+        &self,
+        request: Request<SeekRequest>,
+    ) -> Result<Response<MusicControlResponse>, Status> {
+        let request = request.into_inner();
+        let position = Duration::from_millis(request.position_ms as u64);
+        MUSIC
+            .lock()
+            .unwrap()
+            .seek(&music_bot(request.bot_name), position)?;
+
+        Ok(Response::new(MusicControlResponse::default()))
+    }
+
+    async fn set_loop(
+        &self,
+        request: Request<SetLoopRequest>,
+    ) -> Result<Response<MusicControlResponse>, Status> {
+        let request = request.into_inner();
+        let mode = loop_mode(request.mode)?;
+        MUSIC
+            .lock()
+            .unwrap()
+            .set_loop(&music_bot(request.bot_name), mode)?;
+
+        Ok(Response::new(MusicControlResponse::default()))
+    }
+
+    async fn set_volume(
+        &self,
+        request: Request<SetVolumeRequest>,
+    ) -> Result<Response<MusicControlResponse>, Status> {
+        let request = request.into_inner();
+        MUSIC
+            .lock()
+            .unwrap()
+            .set_volume(&music_bot(request.bot_name), request.volume)?;
+
+        Ok(Response::new(MusicControlResponse::default()))
+    }
+
+    async fn get_queue(
+        &self,
+        request: Request<GetQueueRequest>,
+    ) -> Result<Response<GetQueueResponse>, Status> {
+        let bot = music_bot(request.into_inner().bot_name);
+        let info = MUSIC.lock().unwrap().info(&bot)?;
+        let loop_mode = match info.loop_mode {
+            music::Loop::Off => LoopMode::Off,
+            music::Loop::Track => LoopMode::Track,
+            music::Loop::Queue => LoopMode::Queue,
+        };
+
+        Ok(Response::new(GetQueueResponse {
+            current: info.current.map(track),
+            position_ms: info.position.as_millis() as u32,
+            paused: info.paused,
+            volume: info.volume,
+            loop_mode: loop_mode as i32,
+            queue: info.queue.into_iter().map(track).collect(),
+        }))
+    }
+}
+
 pub fn init(addr: &str) {
     std::panic::set_hook(Box::new(|panic| {
         let panic = format!("{}", panic);
         ffi::log_error(&panic);
     }));
 
+    let config = config::Config::load();
+    PLAYOUT.lock().unwrap().configure(&config);
+    BOTS.lock().unwrap().configure(&config);
+    MUSIC.lock().unwrap().configure(&config);
+    *CONFIG.write().unwrap() = config;
+
     let addr = match addr.parse() {
         Ok(addr) => addr,
         Err(err) => {
@@ -141,28 +772,281 @@ pub fn init(addr: &str) {
 pub async fn main(addr: SocketAddr) {
     let vsimpl = VoiceServiceImpl {};
     let svc = VoiceServiceServer::new(vsimpl);
-    if let Err(err) = Server::builder().add_service(svc).serve(addr).await {
+    let music = MusicServiceServer::new(MusicServiceImpl {});
+    if let Err(err) = Server::builder()
+        .add_service(svc)
+        .add_service(music)
+        .serve(addr)
+        .await
+    {
         ffi::log_error(&format!("{}", err));
     }
 }
 
 pub fn shutdown() {
+    BOTS.lock().unwrap().kick_all();
+
     unsafe {
         drop(RUNTIME_GUARD.take());
         drop(RUNTIME.take());
     }
 }
 
+/// Opens a file below `root` off the game thread.
+async fn open_file(root: String, path: String) -> error::Result<(PathBuf, file::Decoder)> {
+    tokio::task::spawn_blocking(move || {
+        let path = file::resolve(&root, &path)?;
+        let decoder = file::Decoder::open(&path)?;
+        Ok((path, decoder))
+    })
+    .await
+    .map_err(|err| Status::internal(format!("cannot open file: {}", err)))?
+}
+
+/// Starts playing a file for a plugin, reporting through `FILE_EVENTS`.
+///
+/// `client_index` is a 0-based slot, or -1 for the extension's default bot. The file is
+/// opened in the background, so the playback id is returned at once and a file that
+/// cannot be played is reported as finished with an error.
+pub fn play_file(client_index: i32, path: &str, priority: i32) -> error::Result<u64> {
+    let runtime = match unsafe { RUNTIME.as_ref() } {
+        Some(runtime) => runtime,
+        None => return Err(Status::unavailable("voice server is not running").into()),
+    };
+    let config = CONFIG.read().unwrap().clone();
+
+    let options = playout::Options {
+        priority,
+        preemption: playout::Preemption::Duck,
+        impersonate: config.allow_impersonation,
+    };
+    let writer = PLAYOUT.lock().unwrap().open(
+        playout::Address::Slot(client_index),
+        "plugin".to_string(),
+        options,
+    );
+    let id = writer.id();
+    let buffer_frames = playout::frames_for(config.stream_buffer).max(1);
+    let path = path.to_string();
+
+    runtime.spawn(async move {
+        // A file that cannot be opened drops the writer, which cancels the stream.
+        let result = async {
+            let (_, decoder) = open_file(config.file_root, path).await?;
+            let duration = decoder.duration();
+            file::play(decoder, writer, buffer_frames, |position| {
+                FILE_EVENTS.lock().unwrap().push(FileEvent::Progress {
+                    id,
+                    position,
+                    duration,
+                });
+                true
+            })
+            .await
+        }
+        .await;
+        FILE_EVENTS.lock().unwrap().push(FileEvent::Finished {
+            id,
+            error: result.err(),
+        });
+    });
+
+    Ok(id)
+}
+
+/// Queues a file on a music bot for a plugin; an empty `bot` is the default bot name.
+///
+/// The track takes its place in the queue at once while the file is opened in the
+/// background; a file that cannot be played is logged and dropped from the queue.
+pub fn music_enqueue(bot: &str, path: &str) -> error::Result<u64> {
+    let runtime = match unsafe { RUNTIME.as_ref() } {
+        Some(runtime) => runtime,
+        None => return Err(Status::unavailable("voice server is not running").into()),
+    };
+    let file_root = CONFIG.read().unwrap().file_root.clone();
+    let bot = music_bot(bot.to_string());
+    let path = path.to_string();
+
+    let (tx, rx) = oneshot::channel();
+    let id = MUSIC
+        .lock()
+        .unwrap()
+        .enqueue(&bot, path.clone(), None, music::Source::Opening(rx));
+
+    runtime.spawn(async move {
+        let opened = open_file(file_root, path.clone()).await;
+        if let Err(err) = &opened {
+            // Nobody waits for a track still in the queue, so report it here instead.
+            if MUSIC.lock().unwrap().remove(&bot, id) {
+                ffi::log_error(&format!("cannot play {}: {}", path, err));
+                return;
+            }
+        }
+        let _ = tx.send(opened);
+    });
+
+    Ok(id)
+}
+
+pub fn music_skip(bot: &str) -> error::Result<()> {
+    MUSIC.lock().unwrap().skip(&music_bot(bot.to_string()))
+}
+
+pub fn music_stop(bot: &str) -> error::Result<()> {
+    MUSIC.lock().unwrap().stop(&music_bot(bot.to_string()))
+}
+
+pub fn music_set_paused(bot: &str, paused: bool) -> error::Result<()> {
+    MUSIC
+        .lock()
+        .unwrap()
+        .set_paused(&music_bot(bot.to_string()), paused)
+}
+
+pub fn music_seek(bot: &str, position_ms: u32) -> error::Result<()> {
+    MUSIC.lock().unwrap().seek(
+        &music_bot(bot.to_string()),
+        Duration::from_millis(position_ms as u64),
+    )
+}
+
+pub fn music_set_loop(bot: &str, mode: i32) -> error::Result<()> {
+    let mode = loop_mode(mode)?;
+    MUSIC
+        .lock()
+        .unwrap()
+        .set_loop(&music_bot(bot.to_string()), mode)
+}
+
+pub fn music_shuffle(bot: &str) -> error::Result<()> {
+    MUSIC.lock().unwrap().shuffle(&music_bot(bot.to_string()))
+}
+
+pub fn music_set_volume(bot: &str, volume: f32) -> error::Result<()> {
+    MUSIC
+        .lock()
+        .unwrap()
+        .set_volume(&music_bot(bot.to_string()), volume)
+}
+
+/// Slot of the extension's fake client `name`, creating it if it is not in game.
+fn resolve_bot(
+    bots: &mut bots::Registry,
+    name: &str,
+    now: Instant,
+) -> error::Result<playout::Resolved> {
+    match bots.resolve(name, now) {
+        Some(client_index) => Ok(playout::Resolved {
+            client_index,
+            real_player: false,
+        }),
+        None => Err(Status::unavailable(format!("cannot create bot {}", name)).into()),
+    }
+}
+
+fn resolve_address(
+    bots: &mut bots::Registry,
+    address: &playout::Address,
+    now: Instant,
+) -> error::Result<playout::Resolved> {
+    let client_index = match *address {
+        playout::Address::Slot(-1) => return resolve_bot(bots, bots::DEFAULT_NAME, now),
+        playout::Address::Bot(ref name) => return resolve_bot(bots, name, now),
+        playout::Address::SteamId(steamid) => match ffi::find_client_by_steamid(steamid) {
+            -1 => {
+                return Err(Status::not_found(format!("player {} is not in game", steamid)).into())
+            }
+            client_index => client_index,
+        },
+        playout::Address::Slot(client_index) => client_index,
+    };
+
+    let info = ffi::get_client_info(client_index);
+    if !info.in_game {
+        return Err(Status::not_found(format!("client {} is not in game", client_index)).into());
+    }
+
+    // Other fake clients may be game bots a human has taken over, so only the
+    // extension's own bots are free to speak through.
+    Ok(playout::Resolved {
+        client_index,
+        real_player: !bots.owns(client_index, info.userid),
+    })
+}
+
 pub fn on_gameframe() {
+    let now = Instant::now();
+    let packets = {
+        let mut bots = BOTS.lock().unwrap();
+        PLAYOUT
+            .lock()
+            .unwrap()
+            .tick(now, |address| resolve_address(&mut bots, address, now))
+    };
     {
-        let mut pending = SENDVOICEPKTS.lock().unwrap();
-        while let Some((client_index, data)) = pending.pop_front() {
+        // Injected audio bypasses on_recv_voicedata, so the mix picks it up here.
+        let mut mixdown = MIXDOWN.lock().unwrap();
+        let injected = mixdown.wants_injected();
+        for (client_index, data) in packets {
+            if injected {
+                mixdown.push_injected(&ffi::get_client_info(client_index), &data, now);
+            }
             ffi::send_client_voice(client_index, &data);
         }
     }
 
+    {
+        let mut bots = BOTS.lock().unwrap();
+        bots.restore();
+        bots.kick_idle(now);
+    }
+
+    EVENTS
+        .lock()
+        .unwrap()
+        .tick(MAXPLAYERS as i32, ffi::get_client_info);
+
+    let events = std::mem::take(&mut *FILE_EVENTS.lock().unwrap());
+    for event in events {
+        match event {
+            FileEvent::Progress {
+                id,
+                position,
+                duration,
+            } => ffi::forward_file_progress(
+                id,
+                position.as_millis() as u32,
+                duration.unwrap_or_default().as_millis() as u32,
+            ),
+            FileEvent::Finished { id, error } => ffi::forward_file_finished(
+                id,
+                &error.map(|err| err.to_string()).unwrap_or_default(),
+            ),
+        }
+    }
+
+    let now_playing = MUSIC.lock().unwrap().take_events();
+    for event in now_playing {
+        match event.track {
+            Some(track) => ffi::forward_music_now_playing(
+                &event.bot,
+                track.id,
+                &track.title,
+                track.duration.unwrap_or_default().as_millis() as u32,
+            ),
+            None => ffi::forward_music_now_playing(&event.bot, 0, "", 0),
+        }
+    }
+
+    send_spurt_tails(now);
+    send_timelines(now);
+
     {
         let mut senders = VOICESENDERS.lock().unwrap();
+        let tick = ffi::get_game_tick();
+        for sender in senders.iter_mut() {
+            sender.tick(now, tick);
+        }
         let mut i = 0;
         while i < senders.len() {
             if senders[i].is_closed() {
@@ -174,14 +1058,176 @@ pub fn on_gameframe() {
     }
 }
 
-pub fn on_recv_voicedata(idx: usize, volume: f32, steamid: u64, audio_data: &[u8]) -> Vec<u8> {
-    if audio_data.is_empty() {
+/// Sends what the subscribers' transcoders held back at the end of a talk spurt, so the
+/// last Opus frame of every spurt is padded and delivered instead of waiting for the
+/// speaker's next one.
+fn send_spurt_tails(now: Instant) {
+    let timestamp_us = recv::unix_micros(SystemTime::now());
+    let tick = ffi::get_game_tick();
+    let mut senders = VOICESENDERS.lock().unwrap();
+    // Every subscriber's tail of a spurt ending on this frame shares a sequence number.
+    let mut spurt_sequences: HashMap<i32, Option<u64>> = HashMap::new();
+    for sender in senders.iter_mut() {
+        if !sender.wants_packets() {
+            continue;
+        }
+
+        for (client_index, output) in sender.finish_quiet(now) {
+            let client = ffi::get_client_info(client_index);
+            if output.is_empty() || !client.in_game {
+                continue;
+            }
+            let speaker = recv::speaker(&client);
+            if !sender.wants_speaker(&speaker) {
+                continue;
+            }
+
+            let sequence = *spurt_sequences.entry(client_index).or_insert_with(|| {
+                let mut sequences = SEQUENCES.lock().unwrap();
+                let sequence = &mut sequences[client_index as usize];
+                if sequence.userid != client.userid {
+                    return None;
+                }
+                sequence.next += 1;
+
+                Some(sequence.next - 1)
+            });
+            let sequence = match sequence {
+                Some(sequence) => sequence,
+                None => continue,
+            };
+            let format = sender.format();
+            sender.send(RecvVoiceResponse {
+                steamid: client.steamid,
+                audio_data: output.audio_data,
+                speaker: Some(speaker),
+                sequence,
+                timestamp_us,
+                tick,
+                encoding: format.encoding() as i32,
+                sample_rate: format.sample_rate(),
+                opus_packets: output.opus_packets,
+                frame_kind: FrameKind::Voice as i32,
+                talk_event: None,
+                lagged: None,
+            });
+        }
+    }
+}
+
+/// Sends the speaker timeline frames that became due to continuous subscribers and the mix.
+fn send_timelines(now: Instant) {
+    let mut senders = VOICESENDERS.lock().unwrap();
+    let mut timelines = TIMELINES.lock().unwrap();
+    let mut mixdown = MIXDOWN.lock().unwrap();
+    let tick = ffi::get_game_tick();
+    if !senders.iter().any(|sender| sender.is_continuous()) && !mixdown.is_active() {
+        timelines.clear();
+        mixdown.tick(now, tick, ffi::get_client_info);
+        return;
+    }
+
+    let frames = timelines.tick(now, ffi::get_client_info);
+    for frame in frames.iter() {
+        let timestamp_us = recv::unix_micros(frame.timestamp);
+
+        let mut outputs: HashMap<recv::Format, recv::Output> = HashMap::new();
+        for sender in senders.iter_mut() {
+            if !sender.is_continuous() || !sender.wants(&frame.speaker, 0) {
+                continue;
+            }
+
+            let format = sender.format();
+            let output = outputs
+                .entry(format)
+                .or_insert_with(|| timelines.output(frame, format))
+                .clone();
+            sender.send(RecvVoiceResponse {
+                steamid: frame.speaker.steamid,
+                audio_data: output.audio_data,
+                speaker: Some(frame.speaker.clone()),
+                sequence: frame.sequence,
+                timestamp_us,
+                tick,
+                encoding: format.encoding() as i32,
+                sample_rate: format.sample_rate(),
+                opus_packets: output.opus_packets,
+                frame_kind: frame.kind as i32,
+                talk_event: None,
+                lagged: None,
+            });
+        }
+    }
+    timelines.retain(
+        &senders
+            .iter()
+            .filter(|sender| sender.is_continuous())
+            .map(|sender| sender.format())
+            .collect(),
+    );
+
+    mixdown.push(&frames);
+    mixdown.tick(now, tick, ffi::get_client_info);
+}
+
+pub fn on_map_start(map: &str) {
+    BOTS.lock().unwrap().on_map_start();
+    EVENTS.lock().unwrap().map_start(map);
+}
+
+pub fn on_map_end() {
+    EVENTS.lock().unwrap().map_end();
+}
+
+pub fn on_client_put_in_server(client: &ffi::ClientInfo) {
+    EVENTS.lock().unwrap().push(events::connect_event(client));
+}
+
+pub fn on_client_disconnecting(client: &ffi::ClientInfo) {
+    EVENTS.lock().unwrap().push(events::player_event(
+        GameEventKind::PlayerDisconnect,
+        client,
+    ));
+}
+
+/// `client` is about to move from `old_team` to `team`.
+pub fn on_client_team(client: &ffi::ClientInfo, team: i32, old_team: i32) {
+    let mut event = events::player_event(GameEventKind::TeamChange, client);
+    if let Some(player) = event.player.as_mut() {
+        player.team = team;
+    }
+    event.old_team = old_team;
+    EVENTS.lock().unwrap().push(event);
+}
+
+pub fn on_round_start() {
+    EVENTS
+        .lock()
+        .unwrap()
+        .push(events::event(GameEventKind::RoundStart));
+}
+
+pub fn on_round_end(winner: i32, reason: i32) {
+    EVENTS.lock().unwrap().push(GameEvent {
+        winner,
+        reason,
+        ..events::event(GameEventKind::RoundEnd)
+    });
+}
+
+pub fn on_recv_voicedata(client: &ffi::ClientInfo, volume: f32, audio_data: &[u8]) -> Vec<u8> {
+    if audio_data.is_empty() || client.client_index < 0 {
+        return audio_data.to_vec();
+    }
+
+    let idx = client.client_index as usize;
+    if idx >= MAXPLAYERS {
         return audio_data.to_vec();
     }
 
     let frames = audio_data.len() / 64;
 
-    let (data, mut input) = {
+    let mut input = {
         if idx >= DECODERS.len() {
             return audio_data.to_vec();
         }
@@ -200,15 +1246,12 @@ pub fn on_recv_voicedata(idx: usize, volume: f32, steamid: u64, audio_data: &[u8
             };
         }
 
-        let mut data = vec![0; input.len() * 2];
-        let input_iter = input.as_slice().iter();
-        for (data, input) in data.as_mut_slice().chunks_mut(2).zip(input_iter) {
-            data.copy_from_slice(&input.to_le_bytes());
-        }
-
-        (data, input)
+        input
     };
 
+    let pcm = input.clone();
+    let level = recv::level(&pcm);
+
     for i in input.iter_mut() {
         *i = (*i as f64 * volume as f64) as i16
     }
@@ -234,21 +1277,64 @@ pub fn on_recv_voicedata(idx: usize, volume: f32, steamid: u64, audio_data: &[u8
         ret
     };
 
+    let sequence = {
+        let mut sequences = SEQUENCES.lock().unwrap();
+        let sequence = &mut sequences[idx];
+        if sequence.userid != client.userid {
+            *sequence = Sequence {
+                userid: client.userid,
+                next: 0,
+            };
+        }
+        sequence.next += 1;
+
+        sequence.next - 1
+    };
+    let timestamp_us = recv::unix_micros(SystemTime::now());
+    let tick = ffi::get_game_tick();
+
+    let speaker = recv::speaker(client);
+
     let mut senders = VOICESENDERS.lock().unwrap();
+    senders.retain(|sender| !sender.is_closed());
 
-    let mut i = 0;
-    while i < senders.len() {
-        if senders[i].is_closed() {
-            senders.remove(i);
+    let now = Instant::now();
+    for sender in senders.iter_mut() {
+        if sequence == 0 {
+            sender.reset(client.client_index);
+        }
+        if !sender.wants_speaker(&speaker) {
             continue;
         }
 
-        let resp = RecvVoiceResponse {
-            steamid,
-            audio_data: data.clone(),
-        };
-        let _ = senders[i].try_send(Ok(resp));
-        i += 1;
+        // Talk boundaries follow the segmenter's own VAD level, not the packet filter's.
+        sender.segment(&speaker, client.userid, &pcm, audio_data, now, tick);
+        if !sender.wants_packets() || !sender.wants(&speaker, level) {
+            continue;
+        }
+
+        let format = sender.format();
+        let output = sender.output(client.client_index, &pcm, audio_data, now);
+        sender.send(RecvVoiceResponse {
+            steamid: client.steamid,
+            audio_data: output.audio_data,
+            speaker: Some(speaker.clone()),
+            sequence,
+            timestamp_us,
+            tick,
+            encoding: format.encoding() as i32,
+            sample_rate: format.sample_rate(),
+            opus_packets: output.opus_packets,
+            frame_kind: FrameKind::Voice as i32,
+            talk_event: None,
+            lagged: None,
+        });
+    }
+    if senders.iter().any(|sender| sender.is_continuous()) || MIXDOWN.lock().unwrap().is_active() {
+        TIMELINES
+            .lock()
+            .unwrap()
+            .push(client, volume, audio_data, now);
     }
 
     ret
@@ -256,17 +1342,55 @@ pub fn on_recv_voicedata(idx: usize, volume: f32, steamid: u64, audio_data: &[u8
 
 #[cxx::bridge(namespace = "ext")]
 mod ffi {
+    struct ClientInfo {
+        client_index: i32,
+        in_game: bool,
+        fake_client: bool,
+        userid: i32,
+        steamid: u64,
+        name: Vec<u8>,
+        team: i32,
+        alive: bool,
+    }
+
     extern "Rust" {
         fn init(addr: &str);
         fn shutdown();
         fn on_gameframe();
-        fn on_recv_voicedata(idx: usize, volume: f32, steamid: u64, audio_data: &[u8]) -> Vec<u8>;
+        fn on_map_start(map: &str);
+        fn on_map_end();
+        fn on_client_put_in_server(client: &ClientInfo);
+        fn on_client_disconnecting(client: &ClientInfo);
+        fn on_client_team(client: &ClientInfo, team: i32, old_team: i32);
+        fn on_round_start();
+        fn on_round_end(winner: i32, reason: i32);
+        fn on_recv_voicedata(client: &ClientInfo, volume: f32, audio_data: &[u8]) -> Vec<u8>;
+        fn play_file(client_index: i32, path: &str, priority: i32) -> Result<u64>;
+        fn music_enqueue(bot: &str, path: &str) -> Result<u64>;
+        fn music_skip(bot: &str) -> Result<()>;
+        fn music_stop(bot: &str) -> Result<()>;
+        fn music_set_paused(bot: &str, paused: bool) -> Result<()>;
+        fn music_seek(bot: &str, position_ms: u32) -> Result<()>;
+        fn music_set_loop(bot: &str, mode: i32) -> Result<()>;
+        fn music_shuffle(bot: &str) -> Result<()>;
+        fn music_set_volume(bot: &str, volume: f32) -> Result<()>;
     }
 
     unsafe extern "C++" {
         include!("extension.h");
 
         fn send_client_voice(client_index: i32, audio_data: &[u8]);
+        fn find_client_by_steamid(steamid: u64) -> i32;
+        fn get_client_info(client_index: i32) -> ClientInfo;
+        fn create_fake_client(name: &str) -> i32;
+        fn kick_client(userid: i32, reason: &str);
+        fn change_client_team(client_index: i32, team: i32);
+        fn get_game_tick() -> i32;
+        fn get_config_value(key: &str) -> String;
+        fn client_listening(receiver: i32, sender: i32) -> bool;
+        fn forward_file_progress(id: u64, position_ms: u32, duration_ms: u32);
+        fn forward_file_finished(id: u64, error: &str);
+        fn forward_music_now_playing(bot: &str, track_id: u64, title: &str, duration_ms: u32);
         fn log_error(msg: &str);
     }
 }
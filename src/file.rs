@@ -0,0 +1,364 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{self, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+use tonic::Status;
+
+use crate::coder::{self, Framer, InputFormat};
+use crate::error::Result;
+use crate::input::OggOpus;
+use crate::playout::StreamWriter;
+
+/// How often playback progress is reported.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Bytes read from an Ogg/Opus file at a time.
+const READ_SIZE: usize = 16 * 1024;
+
+/// Resolves `path` inside the sandbox directory `root`.
+///
+/// Only plain relative paths are accepted, and the result is checked again after symlinks
+/// are resolved so a link cannot point outside the sandbox.
+pub fn resolve(root: &str, path: &str) -> Result<PathBuf> {
+    if root.is_empty() {
+        return Err(Status::failed_precondition("file playback is disabled").into());
+    }
+    let root = Path::new(root).canonicalize().map_err(|err| {
+        Status::failed_precondition(format!("file directory is unavailable: {}", err))
+    })?;
+
+    let relative = Path::new(path);
+    let plain = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if path.is_empty() || !plain {
+        return Err(Status::invalid_argument(format!(
+            "{} is not a path inside the file directory",
+            path
+        ))
+        .into());
+    }
+
+    let full = root
+        .join(relative)
+        .canonicalize()
+        .map_err(|_| Status::not_found(format!("{} does not exist", path)))?;
+    if !full.starts_with(&root) {
+        return Err(Status::permission_denied(format!(
+            "{} leads outside the file directory",
+            path
+        ))
+        .into());
+    }
+    if !full.is_file() {
+        return Err(Status::not_found(format!("{} is not a file", path)).into());
+    }
+
+    Ok(full)
+}
+
+fn decode_error(err: Error) -> Status {
+    Status::invalid_argument(format!("cannot decode file: {}", err))
+}
+
+fn io_error(err: std::io::Error) -> Status {
+    Status::internal(format!("cannot read file: {}", err))
+}
+
+enum Source {
+    Symphonia {
+        reader: Box<dyn FormatReader>,
+        decoder: Box<dyn codecs::Decoder>,
+        track_id: u32,
+        time_base: Option<TimeBase>,
+    },
+    OggOpus {
+        path: PathBuf,
+        file: File,
+        stream: Box<OggOpus>,
+    },
+}
+
+/// Decodes an audio file on disk into engine frames.
+///
+/// Ogg/Opus goes through the extension's own Opus decoder; WAV, FLAC, Ogg/Vorbis and MP3
+/// go through symphonia.
+pub struct Decoder {
+    source: Source,
+    format: InputFormat,
+    framer: Framer,
+    duration: Option<Duration>,
+    /// Decoded samples still to drop to land exactly on a seek target.
+    skip: usize,
+    done: bool,
+}
+
+impl Decoder {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).map_err(io_error)?;
+
+        let mut magic = [0; 36];
+        let read = file.read(&mut magic).map_err(io_error)?;
+        if read == magic.len() && magic.starts_with(b"OggS") && &magic[28..36] == b"OpusHead" {
+            let format = InputFormat {
+                sample_rate: coder::OPUS_SAMPLE_RATE,
+                ..InputFormat::default()
+            };
+
+            return Ok(Self {
+                source: Source::OggOpus {
+                    path: path.to_path_buf(),
+                    file: File::open(path).map_err(io_error)?,
+                    stream: Box::new(OggOpus::new()),
+                },
+                format,
+                framer: Framer::with_format(format),
+                duration: None,
+                skip: 0,
+                done: false,
+            });
+        }
+
+        let file = File::open(path).map_err(io_error)?;
+        let source = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+            hint.with_extension(extension);
+        }
+        let probed = symphonia::default::get_probe()
+            .format(
+                &hint,
+                source,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .map_err(decode_error)?;
+
+        let reader = probed.format;
+        let track = reader
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| Status::invalid_argument("file has no audio track"))?;
+        let params = &track.codec_params;
+        let sample_rate = params
+            .sample_rate
+            .ok_or_else(|| Status::invalid_argument("file has no sample rate"))?;
+        let duration = params
+            .n_frames
+            .map(|frames| Duration::from_nanos(frames * 1_000_000_000 / sample_rate as u64));
+        let decoder = symphonia::default::get_codecs()
+            .make(params, &DecoderOptions::default())
+            .map_err(decode_error)?;
+        let track_id = track.id;
+        let time_base = params.time_base;
+        let format = InputFormat {
+            sample_rate,
+            ..InputFormat::default()
+        };
+
+        Ok(Self {
+            source: Source::Symphonia {
+                reader,
+                decoder,
+                track_id,
+                time_base,
+            },
+            format,
+            framer: Framer::with_format(format),
+            duration,
+            skip: 0,
+            done: false,
+        })
+    }
+
+    /// Length of the file, if its container says.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    /// Decodes the next chunk of the file, returning `None` once everything was returned.
+    pub fn next(&mut self) -> Result<Option<Vec<Vec<i16>>>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let pcm = match &mut self.source {
+            Source::Symphonia {
+                reader,
+                decoder,
+                track_id,
+                ..
+            } => {
+                let packet = match reader.next_packet() {
+                    Ok(packet) => Some(packet),
+                    Err(Error::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => None,
+                    Err(err) => return Err(decode_error(err).into()),
+                };
+                match packet {
+                    Some(packet) if packet.track_id() != *track_id => Some(Vec::new()),
+                    Some(packet) => match decoder.decode(&packet) {
+                        Ok(decoded) => {
+                            let spec = *decoded.spec();
+                            let mut samples =
+                                SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+                            samples.copy_interleaved_ref(decoded);
+                            Some(downmix(samples.samples(), spec.channels.count()))
+                        }
+                        // A corrupt packet is skipped rather than ending playback.
+                        Err(Error::DecodeError(_)) => Some(Vec::new()),
+                        Err(err) => return Err(decode_error(err).into()),
+                    },
+                    None => None,
+                }
+            }
+            Source::OggOpus { file, stream, .. } => {
+                let mut data = vec![0; READ_SIZE];
+                match file.read(&mut data).map_err(io_error)? {
+                    0 => None,
+                    read => Some(stream.push(&data[..read])?),
+                }
+            }
+        };
+
+        Ok(Some(match pcm {
+            Some(pcm) => {
+                let skip = self.skip.min(pcm.len());
+                self.skip -= skip;
+                self.framer.push_samples(&pcm[skip..])
+            }
+            None => {
+                self.done = true;
+                self.framer.finish()
+            }
+        }))
+    }
+}
+
+impl Decoder {
+    /// Moves playback to `position`, dropping whatever was decoded but not returned yet.
+    pub fn seek(&mut self, position: Duration) -> Result<()> {
+        let sample_rate = self.format.sample_rate as f64;
+
+        self.skip = match &mut self.source {
+            Source::Symphonia {
+                reader,
+                decoder,
+                track_id,
+                time_base,
+            } => {
+                let seeked = reader
+                    .seek(
+                        SeekMode::Accurate,
+                        SeekTo::Time {
+                            time: Time::from(position.as_secs_f64()),
+                            track_id: Some(*track_id),
+                        },
+                    )
+                    .map_err(decode_error)?;
+                decoder.reset();
+
+                // The reader stops at a packet boundary at or before the target.
+                match time_base {
+                    Some(time_base) => {
+                        let seconds = |ts| {
+                            let time = time_base.calc_time(ts);
+                            time.seconds as f64 + time.frac
+                        };
+                        let early = seconds(seeked.required_ts) - seconds(seeked.actual_ts);
+                        (early.max(0.0) * sample_rate) as usize
+                    }
+                    None => 0,
+                }
+            }
+            // Ogg/Opus files carry no index, so the file is decoded again from the start.
+            Source::OggOpus { path, file, stream } => {
+                *file = File::open(path).map_err(io_error)?;
+                **stream = OggOpus::new();
+                (position.as_secs_f64() * sample_rate) as usize
+            }
+        };
+        self.framer = Framer::with_format(self.format);
+        self.done = false;
+
+        Ok(())
+    }
+}
+
+/// Averages interleaved float samples into mono s16.
+fn downmix(samples: &[f32], channels: usize) -> Vec<i16> {
+    samples
+        .chunks_exact(channels.max(1))
+        .map(|frame| {
+            let mono = frame.iter().sum::<f32>() / frame.len() as f32;
+            (mono * 32768.0)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+        })
+        .collect()
+}
+
+/// Decodes `decoder` into `writer`, keeping about `buffer_frames` queued ahead of playout,
+/// and waits for the file to finish playing.
+///
+/// `progress` is called with the position played so far about once per
+/// `PROGRESS_INTERVAL`; returning false stops playback. Returns the final position.
+pub async fn play<F>(
+    mut decoder: Decoder,
+    writer: StreamWriter,
+    buffer_frames: usize,
+    mut progress: F,
+) -> Result<Duration>
+where
+    F: FnMut(Duration) -> bool,
+{
+    let stopped = || Status::cancelled("playback was stopped");
+    let mut reported = Instant::now();
+
+    while let Some(frames) = tokio::task::block_in_place(|| decoder.next())? {
+        if !frames.is_empty() {
+            writer.push(frames);
+        }
+
+        while tokio::time::timeout(PROGRESS_INTERVAL, writer.wait_below(buffer_frames))
+            .await
+            .is_err()
+        {
+            reported = Instant::now();
+            if !progress(writer.played()) {
+                return Err(stopped().into());
+            }
+        }
+        if let Some(status) = writer.take_failure() {
+            return Err(status.into());
+        }
+
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            reported = Instant::now();
+            if !progress(writer.played()) {
+                return Err(stopped().into());
+            }
+        }
+    }
+
+    writer.end();
+    loop {
+        if let Ok(result) = tokio::time::timeout(PROGRESS_INTERVAL, writer.wait_done()).await {
+            result?;
+            return Ok(writer.played());
+        }
+
+        if !progress(writer.played()) {
+            writer.cancel(stopped());
+            return Err(stopped().into());
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use tonic::Status;
+
+use crate::coder::{self, Framer, InputFormat, OpusDecoder};
+use crate::error::Result;
+use crate::ogg;
+use crate::resample;
+use crate::voiceserver::send_voice_request::Payload;
+use crate::voiceserver::{AudioFormat, SampleType};
+
+/// Most interleaved channels accepted in raw PCM input.
+const MAX_INPUT_CHANNELS: u32 = 8;
+
+fn input_format(format: Option<&AudioFormat>) -> Result<InputFormat> {
+    let format = match format {
+        Some(format) => format,
+        None => return Ok(InputFormat::default()),
+    };
+
+    let sample_rate = match format.sample_rate {
+        0 => coder::SAMPLE_RATE,
+        rate @ resample::MIN_SAMPLE_RATE..=resample::MAX_SAMPLE_RATE => rate,
+        rate => {
+            return Err(Status::invalid_argument(format!(
+                "sample rate {} is outside {}..={}",
+                rate,
+                resample::MIN_SAMPLE_RATE,
+                resample::MAX_SAMPLE_RATE
+            ))
+            .into())
+        }
+    };
+    let channels = match format.channels {
+        0 => 1,
+        channels @ 1..=MAX_INPUT_CHANNELS => channels as usize,
+        channels => {
+            return Err(Status::invalid_argument(format!(
+                "{} channels is more than {}",
+                channels, MAX_INPUT_CHANNELS
+            ))
+            .into())
+        }
+    };
+
+    Ok(InputFormat {
+        sample_rate,
+        channels,
+        sample_type: match format.sample_type() {
+            SampleType::S16le => coder::SampleType::S16Le,
+            SampleType::F32le => coder::SampleType::F32Le,
+        },
+    })
+}
+
+fn opus_error(err: i32) -> Status {
+    Status::invalid_argument(format!("opus decode error: {}", err))
+}
+
+enum Header {
+    Head,
+    Tags,
+    Audio,
+}
+
+/// Decoding state of an Ogg/Opus byte stream.
+pub struct OggOpus {
+    reader: ogg::Reader,
+    decoder: OpusDecoder,
+    header: Header,
+    /// Decoded samples still to drop for the stream's pre-skip.
+    skip: usize,
+    /// Samples decoded from the current link so far, pre-skip included, which is what
+    /// its granule positions count.
+    decoded: u64,
+    gain: f32,
+}
+
+impl OggOpus {
+    pub fn new() -> Self {
+        Self {
+            reader: ogg::Reader::new(),
+            decoder: OpusDecoder::new(),
+            header: Header::Head,
+            skip: 0,
+            decoded: 0,
+            gain: 1.0,
+        }
+    }
+
+    /// Appends `data` and returns the 48 kHz mono samples it completes.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<i16>> {
+        let mut pcm = Vec::new();
+        for packet in self.reader.push(data)? {
+            if packet.first {
+                self.header = Header::Head;
+            }
+
+            match self.header {
+                Header::Head => {
+                    self.read_head(&packet.data)?;
+                    self.header = Header::Tags;
+                }
+                Header::Tags => {
+                    if !packet.data.starts_with(b"OpusTags") {
+                        return Err(Status::invalid_argument("missing OpusTags header").into());
+                    }
+                    self.header = Header::Audio;
+                }
+                Header::Audio => {
+                    let mut decoded = self.decoder.decode(&packet.data).map_err(opus_error)?;
+                    let start = self.decoded;
+                    self.decoded += decoded.len() as u64;
+                    // The granule position of a link's last page marks where its audio
+                    // ends, cutting off the padding of the final packet.
+                    if let Some(end) = packet.granule.filter(|_| packet.last) {
+                        decoded.truncate(end.saturating_sub(start) as usize);
+                    }
+
+                    let skip = self.skip.min(decoded.len());
+                    self.skip -= skip;
+
+                    let gain = self.gain;
+                    pcm.extend(decoded[skip..].iter().map(|sample| {
+                        (*sample as f32 * gain).clamp(i16::MIN as f32, i16::MAX as f32) as i16
+                    }));
+                }
+            }
+        }
+
+        Ok(pcm)
+    }
+
+    /// Parses an OpusHead packet, starting a fresh decoder for the new stream.
+    fn read_head(&mut self, head: &[u8]) -> Result<()> {
+        if head.len() < 19 || !head.starts_with(b"OpusHead") {
+            return Err(Status::invalid_argument("missing OpusHead header").into());
+        }
+        if head[8] & 0xf0 != 0 {
+            return Err(Status::invalid_argument("unsupported Ogg/Opus version").into());
+        }
+
+        let channels = head[9];
+        let mapping_family = head[18];
+        if mapping_family != 0 && channels > 2 {
+            return Err(
+                Status::unimplemented("multichannel Ogg/Opus streams are not supported").into(),
+            );
+        }
+
+        let pre_skip = u16::from_le_bytes([head[10], head[11]]);
+        let output_gain = i16::from_le_bytes([head[16], head[17]]);
+
+        self.decoder = OpusDecoder::new();
+        self.skip = pre_skip as usize;
+        self.decoded = 0;
+        // Output gain is in Q7.8 dB.
+        self.gain = 10f32.powf(output_gain as f32 / (20.0 * 256.0));
+
+        Ok(())
+    }
+}
+
+enum Decoder {
+    Pcm,
+    Opus(OpusDecoder),
+    OggOpus(Box<OggOpus>),
+}
+
+/// Turns the payloads of a `SendVoiceData` stream into engine frames.
+///
+/// The kind of the first payload decides how the whole stream is decoded.
+pub struct Input {
+    framer: Framer,
+    decoder: Decoder,
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Self {
+            framer: Framer::new(),
+            decoder: Decoder::Pcm,
+        }
+    }
+}
+
+impl Input {
+    pub fn new(payload: &Payload, format: Option<&AudioFormat>) -> Result<Self> {
+        let opus_format = InputFormat {
+            sample_rate: coder::OPUS_SAMPLE_RATE,
+            ..InputFormat::default()
+        };
+
+        Ok(match payload {
+            Payload::AudioData(_) => Self {
+                framer: Framer::with_format(input_format(format)?),
+                decoder: Decoder::Pcm,
+            },
+            Payload::OpusPacket(_) => Self {
+                framer: Framer::with_format(opus_format),
+                decoder: Decoder::Opus(OpusDecoder::new()),
+            },
+            Payload::OggOpus(_) => Self {
+                framer: Framer::with_format(opus_format),
+                decoder: Decoder::OggOpus(Box::new(OggOpus::new())),
+            },
+        })
+    }
+
+    /// Decodes `payload` and returns every whole frame that is now available.
+    pub fn push(&mut self, payload: Payload) -> Result<Vec<Vec<i16>>> {
+        match (&mut self.decoder, payload) {
+            (Decoder::Pcm, Payload::AudioData(data)) => Ok(self.framer.push(&data)),
+            (Decoder::Opus(decoder), Payload::OpusPacket(packet)) => {
+                let pcm = decoder.decode(&packet).map_err(opus_error)?;
+                Ok(self.framer.push_samples(&pcm))
+            }
+            (Decoder::OggOpus(stream), Payload::OggOpus(data)) => {
+                let pcm = stream.push(&data)?;
+                Ok(self.framer.push_samples(&pcm))
+            }
+            _ => Err(
+                Status::invalid_argument("payload kind changed in the middle of a stream").into(),
+            ),
+        }
+    }
+
+    /// Returns the remaining frames, the last padded to size.
+    pub fn finish(&mut self) -> Vec<Vec<i16>> {
+        self.framer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ogg::tests::packets_page;
+
+    /// A CELT-only 20 ms Opus packet without payload, which decodes to 960 samples.
+    const PACKET: &[u8] = &[0xf8];
+
+    fn head(pre_skip: u16) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 1]);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&48000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        head
+    }
+
+    /// A complete Ogg/Opus link of `packets` whose last page has granule position `end`.
+    fn link(serial: u32, pre_skip: u16, packets: usize, end: u64) -> Vec<u8> {
+        // Flag 0x02 begins the logical stream and 0x04 ends it.
+        let mut data = packets_page(serial, 0x02, 0, &[&head(pre_skip)]);
+        data.extend(packets_page(serial, 0, 0, &[b"OpusTags"]));
+        data.extend(packets_page(serial, 0x04, end, &vec![PACKET; packets]));
+        data
+    }
+
+    #[test]
+    fn ogg_opus_drops_the_pre_skip_and_the_end_padding() {
+        let mut stream = OggOpus::new();
+        let pcm = stream.push(&link(1, 312, 3, 312 + 2000)).unwrap();
+
+        assert_eq!(pcm.len(), 2000);
+    }
+
+    #[test]
+    fn ogg_opus_trims_every_link_of_a_chain() {
+        let mut data = link(1, 100, 1, 100 + 500);
+        data.extend(link(2, 0, 2, 1500));
+
+        let mut stream = OggOpus::new();
+        let mut pcm = Vec::new();
+        for chunk in data.chunks(7) {
+            pcm.extend(stream.push(chunk).unwrap());
+        }
+
+        assert_eq!(pcm.len(), 500 + 1500);
+    }
+
+    #[test]
+    fn ogg_opus_needs_its_headers() {
+        let data = packets_page(1, 0x02, 0, &[PACKET]);
+
+        assert!(OggOpus::new().push(&data).is_err());
+    }
+
+    #[test]
+    fn opus_packets_become_engine_frames() {
+        let payload = Payload::OpusPacket(PACKET.to_vec());
+        let mut input = Input::new(&payload, None).unwrap();
+
+        // 200 ms at 48 kHz is 4410 samples at the engine rate, so 9 frames once padded.
+        let mut frames = Vec::new();
+        for _ in 0..10 {
+            frames.extend(input.push(payload.clone()).unwrap());
+        }
+        frames.extend(input.finish());
+
+        assert_eq!(frames.len(), 9);
+        assert!(frames.iter().all(|frame| frame.len() == coder::FRAME_SIZE));
+    }
+
+    #[test]
+    fn payload_kind_cannot_change() {
+        let payload = Payload::OpusPacket(PACKET.to_vec());
+        let mut input = Input::new(&payload, None).unwrap();
+
+        assert!(input.push(Payload::AudioData(vec![0; 4])).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Instant, SystemTime};
+
+use tokio::sync::mpsc;
+use tonic::Status;
+
+use crate::coder;
+use crate::error::Result;
+use crate::ffi;
+use crate::mixer::{self, Limiter};
+use crate::recv::{self, Format, Transcoder};
+use crate::timeline::{self, Frame, Timelines};
+use crate::voiceserver::{
+    recv_mixed_voice_request, speaker_gain, AudioEncoding, FrameKind, RecvMixedVoiceRequest,
+    RecvMixedVoiceResponse,
+};
+
+/// Highest per-speaker gain a subscription may ask for.
+const MAX_GAIN: f32 = 4.0;
+/// Frames a source may run ahead of the mix before the oldest are dropped.
+const MAX_QUEUED_FRAMES: usize = 3;
+
+/// Player whose perspective a listener mix follows.
+enum Listener {
+    Slot(i32),
+    SteamId(u64),
+}
+
+/// A `RecvMixedVoice` stream, mixing with its own gains, limiter and output format.
+pub struct Subscriber {
+    sender: mpsc::Sender<Result<RecvMixedVoiceResponse, Status>>,
+    listener: Option<Listener>,
+    /// Slot the listener is in this tick, `None` while they are not in game.
+    listener_slot: Option<i32>,
+    steamid_gains: HashMap<u64, f32>,
+    client_gains: HashMap<i32, f32>,
+    listed_only: bool,
+    include_bots: bool,
+    format: Format,
+    limiter: Limiter,
+    transcoder: Transcoder,
+    sequence: u64,
+}
+
+impl Subscriber {
+    pub fn new(
+        sender: mpsc::Sender<Result<RecvMixedVoiceResponse, Status>>,
+        request: &RecvMixedVoiceRequest,
+    ) -> Result<Self> {
+        if request.encoding() == AudioEncoding::Celt {
+            return Err(
+                Status::invalid_argument("mixed voice is only available as PCM or Opus").into(),
+            );
+        }
+        let format = Format::from_encoding(request.encoding(), request.sample_rate)?;
+
+        let mut steamid_gains = HashMap::new();
+        let mut client_gains = HashMap::new();
+        for gain in request.gains.iter() {
+            if !(0.0..=MAX_GAIN).contains(&gain.gain) {
+                return Err(Status::invalid_argument(format!(
+                    "gain must be between 0 and {}",
+                    MAX_GAIN
+                ))
+                .into());
+            }
+            match gain.speaker {
+                Some(speaker_gain::Speaker::Steamid(steamid)) => {
+                    steamid_gains.insert(steamid, gain.gain);
+                }
+                Some(speaker_gain::Speaker::ClientIndex(client_index)) => {
+                    client_gains.insert(client_index, gain.gain);
+                }
+                None => return Err(Status::invalid_argument("gain names no speaker").into()),
+            }
+        }
+
+        let listener = match request.listener {
+            Some(recv_mixed_voice_request::Listener::ListenerClientIndex(client_index)) => {
+                Some(Listener::Slot(client_index))
+            }
+            Some(recv_mixed_voice_request::Listener::ListenerSteamid(steamid)) => {
+                Some(Listener::SteamId(steamid))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            sender,
+            listener,
+            listener_slot: None,
+            steamid_gains,
+            client_gains,
+            listed_only: request.listed_only,
+            include_bots: request.include_bots,
+            format,
+            limiter: Limiter::new(),
+            transcoder: Transcoder::new(format),
+            sequence: 0,
+        })
+    }
+
+    /// Finds the listener's slot for the frames of this tick.
+    fn locate_listener(&mut self) {
+        self.listener_slot = match self.listener {
+            Some(Listener::Slot(client_index)) => Some(client_index),
+            Some(Listener::SteamId(steamid)) => match ffi::find_client_by_steamid(steamid) {
+                -1 => None,
+                client_index => Some(client_index),
+            },
+            None => return,
+        }
+        .filter(|client_index| ffi::get_client_info(*client_index).in_game);
+    }
+
+    /// Gain `frame` is mixed at, or `None` if the subscriber leaves its speaker out.
+    fn gain(&self, frame: &Frame, injected: bool) -> Option<f32> {
+        let speaker = &frame.speaker;
+        let volume = match self.listener {
+            // The game decides who hears whom, including team-only voice and mutes, and
+            // bots are heard like anyone else.
+            Some(_) => match self.listener_slot {
+                Some(listener)
+                    if listener != speaker.client_index
+                        && ffi::client_listening(listener, speaker.client_index) =>
+                {
+                    frame.volume
+                }
+                _ => return None,
+            },
+            None if (speaker.bot || injected) && !self.include_bots => return None,
+            None => 1.0,
+        };
+
+        let listed = self
+            .steamid_gains
+            .get(&speaker.steamid)
+            .filter(|_| speaker.steamid != 0)
+            .or_else(|| self.client_gains.get(&speaker.client_index));
+        match listed {
+            Some(gain) => Some(gain * volume),
+            None if self.listed_only => None,
+            None => Some(volume),
+        }
+    }
+
+    fn send(&mut self, sources: &[(Frame, bool)], timestamp_us: u64, tick: i32) {
+        let mut mix = vec![0; coder::FRAME_SIZE];
+        let mut speakers = Vec::new();
+        for (frame, injected) in sources {
+            let gain = match self.gain(frame, *injected) {
+                Some(gain) => gain,
+                None => continue,
+            };
+            if frame.kind == FrameKind::Silence {
+                continue;
+            }
+
+            mixer::accumulate(&mut mix, &frame.pcm, gain);
+            if gain > 0.0 && !speakers.contains(&frame.speaker) {
+                speakers.push(frame.speaker.clone());
+            }
+        }
+
+        let output = self.transcoder.process(&self.limiter.process(&mix));
+        let _ = self.sender.try_send(Ok(RecvMixedVoiceResponse {
+            audio_data: output.audio_data,
+            opus_packets: output.opus_packets,
+            encoding: self.format.encoding() as i32,
+            sample_rate: self.format.sample_rate(),
+            sequence: self.sequence,
+            timestamp_us,
+            tick,
+            speakers,
+        }));
+        self.sequence += 1;
+    }
+}
+
+struct Clock {
+    start: Instant,
+    start_time: SystemTime,
+    emitted: u64,
+}
+
+/// Mixes speaker timelines into one feed per `RecvMixedVoice` subscriber.
+///
+/// Every source, either a speaker's voice timeline or the timeline of audio injected
+/// through a slot, runs on the server clock at the same rate as the mix, so the mix takes
+/// one frame from each per frame it releases and sources stay sample-aligned.
+#[derive(Default)]
+pub struct Mixdown {
+    subscribers: Vec<Subscriber>,
+    clock: Option<Clock>,
+    /// Frames waiting to be mixed, keyed by whether they were injected and the client slot.
+    sources: HashMap<(bool, i32), VecDeque<Frame>>,
+    injected: Timelines,
+}
+
+impl Mixdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&mut self, subscriber: Subscriber) {
+        self.subscribers.push(subscriber);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.subscribers
+            .iter()
+            .any(|subscriber| !subscriber.sender.is_closed())
+    }
+
+    /// Whether any subscriber mixes in the audio the extension injects.
+    pub fn wants_injected(&self) -> bool {
+        self.subscribers
+            .iter()
+            .filter(|subscriber| !subscriber.sender.is_closed())
+            .any(|subscriber| subscriber.include_bots || subscriber.listener.is_some())
+    }
+
+    /// Queues frames from the voice timelines.
+    pub fn push(&mut self, frames: &[Frame]) {
+        for frame in frames {
+            self.queue(frame.clone(), false);
+        }
+    }
+
+    /// Buffers a packet injected into `client`'s slot by the playout scheduler.
+    pub fn push_injected(&mut self, client: &ffi::ClientInfo, celt: &[u8], now: Instant) {
+        // Injected audio skips the detour that applies the volume map.
+        self.injected.push(client, 1.0, celt, now);
+    }
+
+    fn queue(&mut self, frame: Frame, injected: bool) {
+        let queue = self
+            .sources
+            .entry((injected, frame.speaker.client_index))
+            .or_default();
+        queue.push_back(frame);
+        while queue.len() > MAX_QUEUED_FRAMES {
+            queue.pop_front();
+        }
+    }
+
+    /// Mixes and sends the frames that became due by `now`.
+    pub fn tick<F>(&mut self, now: Instant, tick: i32, client_info: F)
+    where
+        F: FnMut(i32) -> ffi::ClientInfo,
+    {
+        self.subscribers
+            .retain(|subscriber| !subscriber.sender.is_closed());
+        if self.subscribers.is_empty() {
+            self.clock = None;
+            self.sources.clear();
+            self.injected.clear();
+            return;
+        }
+
+        for frame in self.injected.tick(now, client_info) {
+            self.queue(frame, true);
+        }
+        for subscriber in self.subscribers.iter_mut() {
+            subscriber.locate_listener();
+        }
+
+        let clock = self.clock.get_or_insert_with(|| Clock {
+            start: now,
+            start_time: SystemTime::now(),
+            emitted: 0,
+        });
+        let due = timeline::frames_started(now.saturating_duration_since(clock.start));
+        while clock.emitted < due {
+            let mut sources = Vec::with_capacity(self.sources.len());
+            self.sources
+                .retain(|(injected, _), queue| match queue.pop_front() {
+                    Some(frame) => {
+                        sources.push((frame, *injected));
+                        true
+                    }
+                    // The timeline ended.
+                    None => false,
+                });
+
+            let timestamp_us =
+                recv::unix_micros(clock.start_time + timeline::frames_duration(clock.emitted));
+            for subscriber in self.subscribers.iter_mut() {
+                subscriber.send(&sources, timestamp_us, tick);
+            }
+            clock.emitted += 1;
+        }
+    }
+}
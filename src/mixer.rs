@@ -0,0 +1,100 @@
+/// Gain recovery per frame once the signal drops back below full scale.
+const LIMITER_RELEASE: f32 = 0.05;
+
+/// Adds `frame`, scaled by `gain`, into the `mix` accumulator.
+pub fn accumulate(mix: &mut [i32], frame: &[i16], gain: f32) {
+    for (mix, sample) in mix.iter_mut().zip(frame.iter()) {
+        *mix += (*sample as f32 * gain) as i32;
+    }
+}
+
+/// Frame-based peak limiter keeping a mix of several sources within 16-bit range.
+///
+/// Gain drops at once to whatever the loudest sample of a frame needs and recovers
+/// gradually afterwards, ramped across each frame to avoid zipper noise.
+pub struct Limiter {
+    gain: f32,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self { gain: 1.0 }
+    }
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn process(&mut self, mix: &[i32]) -> Vec<i16> {
+        let peak = mix.iter().map(|sample| sample.abs()).max().unwrap_or(0) as f32;
+        let limit = if peak > i16::MAX as f32 {
+            i16::MAX as f32 / peak
+        } else {
+            1.0
+        };
+
+        let target = if limit < self.gain {
+            limit
+        } else {
+            (self.gain + (1.0 - self.gain) * LIMITER_RELEASE).min(limit)
+        };
+
+        let step = (target - self.gain) / mix.len().max(1) as f32;
+        let mut gain = self.gain;
+        let output = mix
+            .iter()
+            .map(|sample| {
+                gain += step;
+                (*sample as f32 * gain.min(limit))
+                    .round()
+                    .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+            })
+            .collect();
+        self.gain = target;
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulate_adds_scaled_sources() {
+        let mut mix = vec![0; 3];
+        accumulate(&mut mix, &[100, -200, 300], 1.0);
+        accumulate(&mut mix, &[100, 100, 100], 0.5);
+
+        assert_eq!(mix, vec![150, -150, 350]);
+    }
+
+    #[test]
+    fn limiter_passes_quiet_mixes_unchanged() {
+        let mut limiter = Limiter::new();
+        let mix = vec![1000, -2000, 3000, i16::MAX as i32];
+
+        assert_eq!(limiter.process(&mix), vec![1000, -2000, 3000, i16::MAX]);
+    }
+
+    #[test]
+    fn limiter_keeps_loud_mixes_in_range_and_recovers() {
+        let mut limiter = Limiter::new();
+        let loud = vec![2 * i16::MAX as i32; 512];
+
+        let output = limiter.process(&loud);
+        // The samples clamp at full scale instead of wrapping around.
+        assert!(output.iter().all(|sample| *sample >= i16::MAX - 1));
+
+        // Gain comes back gradually rather than at once.
+        let quiet = vec![10000; 512];
+        let first = limiter.process(&quiet);
+        assert!(first[511] < 10000);
+        for _ in 0..200 {
+            limiter.process(&quiet);
+        }
+        assert_eq!(limiter.process(&quiet)[511], 10000);
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::{mpsc, oneshot, Notify};
+use tonic::Status;
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::ffi;
+use crate::file;
+use crate::playout;
+
+/// Highest volume a music bot accepts, as a linear gain.
+const MAX_VOLUME: f32 = 4.0;
+
+/// Frames of a streamed track, handed over by its uploader as they are decoded.
+pub type StreamReceiver = mpsc::Receiver<Vec<Vec<i16>>>;
+
+/// What a player does once a track ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Loop {
+    /// Moves on to the next track.
+    Off,
+    /// Plays the current track again.
+    Track,
+    /// Moves the track to the end of the queue.
+    Queue,
+}
+
+pub enum Source {
+    File(PathBuf),
+    /// A file still being resolved and probed off the game thread.
+    Opening(oneshot::Receiver<Result<(PathBuf, file::Decoder)>>),
+    /// Audio uploaded while the track waits and plays; it can be played only once.
+    Stream(StreamReceiver),
+}
+
+struct Track {
+    info: TrackInfo,
+    source: Source,
+}
+
+/// Snapshot of a track for the API.
+#[derive(Clone, Debug)]
+pub struct TrackInfo {
+    pub id: u64,
+    pub title: String,
+    pub duration: Option<Duration>,
+    pub streamed: bool,
+}
+
+/// A change of the track a music bot is playing, `None` once its queue ran out.
+#[derive(Clone, Debug)]
+pub struct NowPlaying {
+    pub bot: String,
+    pub track: Option<TrackInfo>,
+}
+
+/// Snapshot of a player for the API.
+#[derive(Clone, Debug)]
+pub struct PlayerInfo {
+    pub current: Option<TrackInfo>,
+    pub position: Duration,
+    pub paused: bool,
+    pub volume: f32,
+    pub loop_mode: Loop,
+    pub queue: Vec<TrackInfo>,
+}
+
+enum Command {
+    Skip,
+    Stop,
+    Seek(Duration),
+}
+
+enum Outcome {
+    Finished(Track),
+    Skipped(Track),
+    Stopped,
+    Failed,
+}
+
+struct State {
+    queue: VecDeque<Track>,
+    current: Option<TrackInfo>,
+    position: Duration,
+    injection_id: Option<u64>,
+    paused: bool,
+    volume: f32,
+    loop_mode: Loop,
+    command: Option<Command>,
+    running: bool,
+}
+
+/// Playlist of one music bot, played by a task that runs while the queue is non-empty.
+struct Player {
+    state: Mutex<State>,
+    wake: Notify,
+}
+
+impl Player {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                current: None,
+                position: Duration::ZERO,
+                injection_id: None,
+                paused: false,
+                volume: 1.0,
+                loop_mode: Loop::Off,
+                command: None,
+                running: false,
+            }),
+            wake: Notify::new(),
+        }
+    }
+
+    fn command(&self, command: Command) {
+        self.state.lock().unwrap().command = Some(command);
+        self.wake.notify_one();
+    }
+}
+
+/// Shuffles `items` in place with a time-seeded xorshift generator.
+fn shuffle<T>(items: &mut VecDeque<T>) {
+    let mut seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default()
+        | 1;
+    for i in (1..items.len()).rev() {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        items.swap(i, (seed % (i as u64 + 1)) as usize);
+    }
+}
+
+/// Music bots and their playlists.
+///
+/// Each bot plays through the playout scheduler as `Address::Bot`, so it gets its own
+/// fake client from the bot registry and mixes with other injections on the same bot.
+pub struct Library {
+    players: HashMap<String, Arc<Player>>,
+    next_id: u64,
+    buffer_frames: usize,
+    events: Arc<Mutex<Vec<NowPlaying>>>,
+}
+
+impl Default for Library {
+    fn default() -> Self {
+        Self {
+            players: HashMap::new(),
+            next_id: 1,
+            buffer_frames: 1,
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl Library {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn configure(&mut self, config: &Config) {
+        self.buffer_frames = playout::frames_for(config.stream_buffer).max(1);
+    }
+
+    fn player(&self, bot: &str) -> Result<&Arc<Player>> {
+        self.players
+            .get(bot)
+            .ok_or_else(|| Status::not_found(format!("no music bot named {}", bot)).into())
+    }
+
+    /// Adds a track to the end of `bot`'s queue, starting playback if it was idle.
+    ///
+    /// Must be called inside the runtime, which the player task is spawned on.
+    pub fn enqueue(
+        &mut self,
+        bot: &str,
+        title: String,
+        duration: Option<Duration>,
+        source: Source,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let player = self
+            .players
+            .entry(bot.to_string())
+            .or_insert_with(|| Arc::new(Player::new()))
+            .clone();
+        let mut state = player.state.lock().unwrap();
+        state.queue.push_back(Track {
+            info: TrackInfo {
+                id,
+                title,
+                duration,
+                streamed: matches!(source, Source::Stream(_)),
+            },
+            source,
+        });
+
+        if !state.running {
+            state.running = true;
+            tokio::spawn(run(
+                bot.to_string(),
+                player.clone(),
+                self.buffer_frames,
+                self.events.clone(),
+            ));
+        }
+
+        id
+    }
+
+    /// Takes track `id` out of `bot`'s queue, or skips it if it is already playing.
+    ///
+    /// Returns whether the track was still waiting in the queue.
+    pub fn remove(&self, bot: &str, id: u64) -> bool {
+        let player = match self.players.get(bot) {
+            Some(player) => player,
+            None => return false,
+        };
+        let mut state = player.state.lock().unwrap();
+        let queued = state.queue.len();
+        state.queue.retain(|track| track.info.id != id);
+        if state.queue.len() < queued {
+            return true;
+        }
+
+        if state.current.as_ref().map(|track| track.id) == Some(id) {
+            state.command = Some(Command::Skip);
+            drop(state);
+            player.wake.notify_one();
+        }
+        false
+    }
+
+    pub fn skip(&self, bot: &str) -> Result<()> {
+        self.player(bot)?.command(Command::Skip);
+        Ok(())
+    }
+
+    /// Empties the queue and stops the current track.
+    pub fn stop(&self, bot: &str) -> Result<()> {
+        let player = self.player(bot)?;
+        player.state.lock().unwrap().queue.clear();
+        player.command(Command::Stop);
+        Ok(())
+    }
+
+    pub fn set_paused(&self, bot: &str, paused: bool) -> Result<()> {
+        let mut state = self.player(bot)?.state.lock().unwrap();
+        state.paused = paused;
+        if let Some(id) = state.injection_id {
+            crate::PLAYOUT.lock().unwrap().set_paused(id, paused);
+        }
+        Ok(())
+    }
+
+    pub fn seek(&self, bot: &str, position: Duration) -> Result<()> {
+        let player = self.player(bot)?;
+        match &player.state.lock().unwrap().current {
+            Some(track) if track.streamed => {
+                return Err(Status::failed_precondition("streamed tracks cannot seek").into())
+            }
+            Some(_) => {}
+            None => return Err(Status::failed_precondition("nothing is playing").into()),
+        }
+        player.command(Command::Seek(position));
+        Ok(())
+    }
+
+    pub fn set_loop(&self, bot: &str, loop_mode: Loop) -> Result<()> {
+        self.player(bot)?.state.lock().unwrap().loop_mode = loop_mode;
+        Ok(())
+    }
+
+    /// Shuffles the tracks waiting in the queue.
+    pub fn shuffle(&self, bot: &str) -> Result<()> {
+        shuffle(&mut self.player(bot)?.state.lock().unwrap().queue);
+        Ok(())
+    }
+
+    pub fn set_volume(&self, bot: &str, volume: f32) -> Result<()> {
+        if !(0.0..=MAX_VOLUME).contains(&volume) {
+            return Err(Status::invalid_argument(format!(
+                "volume must be between 0 and {}",
+                MAX_VOLUME
+            ))
+            .into());
+        }
+
+        let mut state = self.player(bot)?.state.lock().unwrap();
+        state.volume = volume;
+        if let Some(id) = state.injection_id {
+            crate::PLAYOUT.lock().unwrap().set_gain(id, volume);
+        }
+        Ok(())
+    }
+
+    pub fn info(&self, bot: &str) -> Result<PlayerInfo> {
+        let state = self.player(bot)?.state.lock().unwrap();
+        Ok(PlayerInfo {
+            current: state.current.clone(),
+            position: state.position,
+            paused: state.paused,
+            volume: state.volume,
+            loop_mode: state.loop_mode,
+            queue: state.queue.iter().map(|track| track.info.clone()).collect(),
+        })
+    }
+
+    /// Takes the now-playing changes since the last call, for the game thread's forwards.
+    pub fn take_events(&self) -> Vec<NowPlaying> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+/// Plays `player`'s queue until it runs out.
+async fn run(
+    bot: String,
+    player: Arc<Player>,
+    buffer_frames: usize,
+    events: Arc<Mutex<Vec<NowPlaying>>>,
+) {
+    loop {
+        let track = {
+            let mut state = player.state.lock().unwrap();
+            state.command = None;
+            state.position = Duration::ZERO;
+            match state.queue.pop_front() {
+                Some(track) => {
+                    state.current = Some(track.info.clone());
+                    track
+                }
+                None => {
+                    // Reported under the lock so it cannot overtake a task started after it.
+                    state.current = None;
+                    state.running = false;
+                    events.lock().unwrap().push(NowPlaying { bot, track: None });
+                    return;
+                }
+            }
+        };
+        events.lock().unwrap().push(NowPlaying {
+            bot: bot.clone(),
+            track: Some(track.info.clone()),
+        });
+
+        let outcome = play(&bot, &player, track, buffer_frames).await;
+
+        let mut state = player.state.lock().unwrap();
+        state.injection_id = None;
+        match outcome {
+            Outcome::Finished(track) if !track.info.streamed => match state.loop_mode {
+                Loop::Off => {}
+                Loop::Track => state.queue.push_front(track),
+                Loop::Queue => state.queue.push_back(track),
+            },
+            Outcome::Skipped(track) if !track.info.streamed && state.loop_mode == Loop::Queue => {
+                state.queue.push_back(track)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Plays a single track on `bot`, following the player's commands.
+async fn play(bot: &str, player: &Player, mut track: Track, buffer_frames: usize) -> Outcome {
+    let mut decoder = match &mut track.source {
+        Source::File(path) => match tokio::task::block_in_place(|| file::Decoder::open(path)) {
+            Ok(decoder) => Some(decoder),
+            Err(err) => {
+                ffi::log_error(&format!("cannot play {}: {}", track.info.title, err));
+                return Outcome::Failed;
+            }
+        },
+        Source::Opening(opening) => match opening.await {
+            Ok(Ok((path, decoder))) => {
+                // Replays reopen the file like any other queued one.
+                track.source = Source::File(path);
+                track.info.duration = decoder.duration();
+                player.state.lock().unwrap().current = Some(track.info.clone());
+                Some(decoder)
+            }
+            Ok(Err(err)) => {
+                ffi::log_error(&format!("cannot play {}: {}", track.info.title, err));
+                return Outcome::Failed;
+            }
+            Err(_) => return Outcome::Failed,
+        },
+        Source::Stream(_) => None,
+    };
+
+    let writer = crate::PLAYOUT.lock().unwrap().open(
+        playout::Address::Bot(bot.to_string()),
+        "music".to_string(),
+        playout::Options::default(),
+    );
+    {
+        let mut state = player.state.lock().unwrap();
+        state.injection_id = Some(writer.id());
+        writer.set_paused(state.paused);
+        writer.set_gain(state.volume);
+    }
+
+    // Position of the track at the last seek, and how much had been played by then.
+    let mut offset = Duration::ZERO;
+    let mut played_at_seek = Duration::ZERO;
+    let mut exhausted = false;
+
+    loop {
+        let command = {
+            let mut state = player.state.lock().unwrap();
+            state.position = offset + writer.played().saturating_sub(played_at_seek);
+            state.command.take()
+        };
+        match command {
+            Some(Command::Skip) => {
+                writer.cancel(Status::cancelled("track was skipped"));
+                return Outcome::Skipped(track);
+            }
+            Some(Command::Stop) => {
+                writer.cancel(Status::cancelled("music was stopped"));
+                return Outcome::Stopped;
+            }
+            Some(Command::Seek(position)) => {
+                if let Some(decoder) = &mut decoder {
+                    match tokio::task::block_in_place(|| decoder.seek(position)) {
+                        Ok(()) => {
+                            writer.clear();
+                            offset = position;
+                            played_at_seek = writer.played();
+                            exhausted = false;
+                        }
+                        Err(err) => {
+                            ffi::log_error(&format!("cannot seek {}: {}", track.info.title, err))
+                        }
+                    }
+                }
+            }
+            None => {}
+        }
+
+        if !exhausted {
+            let frames = match (&mut decoder, &mut track.source) {
+                (Some(decoder), _) => match tokio::task::block_in_place(|| decoder.next()) {
+                    Ok(frames) => frames,
+                    Err(err) => {
+                        ffi::log_error(&format!("cannot play {}: {}", track.info.title, err));
+                        None
+                    }
+                },
+                (None, Source::Stream(receiver)) => tokio::select! {
+                    frames = receiver.recv() => frames,
+                    _ = player.wake.notified() => continue,
+                },
+                (None, _) => None,
+            };
+
+            match frames {
+                Some(frames) if !frames.is_empty() => {
+                    writer.push(frames);
+                }
+                Some(_) => {}
+                None => exhausted = true,
+            }
+        }
+
+        // Once everything is decoded, wait for the queue to drain instead of for room.
+        let limit = if exhausted { 1 } else { buffer_frames };
+        let woken = tokio::select! {
+            _ = writer.wait_below(limit) => false,
+            _ = player.wake.notified() => true,
+        };
+
+        if let Some(status) = writer.take_failure() {
+            ffi::log_error(&format!(
+                "{} stopped playing: {}",
+                track.info.title,
+                status.message()
+            ));
+            return Outcome::Failed;
+        }
+
+        if exhausted && !woken {
+            writer.end();
+            return Outcome::Finished(track);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::convert::TryInto;
+
+use tonic::Status;
+
+use crate::error::Result;
+
+const CAPTURE_PATTERN: &[u8] = b"OggS";
+const HEADER_SIZE: usize = 27;
+
+const FLAG_CONTINUED: u8 = 0x01;
+const FLAG_BOS: u8 = 0x02;
+const FLAG_EOS: u8 = 0x04;
+
+/// Granule position of a page on which no packet ends.
+const NO_GRANULE: u64 = u64::MAX;
+
+/// A packet of the followed logical stream.
+pub struct Packet {
+    pub data: Vec<u8>,
+    /// Set on the first packet of a logical stream, including each link of a chained file.
+    pub first: bool,
+    /// Set on the last packet of a logical stream.
+    pub last: bool,
+    /// Granule position of the page this packet ends, if it is the last packet ending there.
+    pub granule: Option<u64>,
+}
+
+lazy_static::lazy_static! {
+    static ref CRC_TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = (i as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                };
+            }
+            *entry = crc;
+        }
+        table
+    };
+}
+
+fn crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+/// Incremental Ogg demuxer that follows the first logical stream it sees.
+///
+/// Pages may be split across any number of `push` calls, and packets may span pages.
+/// Pages of other multiplexed streams are skipped. A stream that begins after the
+/// followed one is picked up as the next link of a chained file, even if the previous
+/// link never marked its end: multiplexed streams all begin before any of them carries
+/// data, so a later beginning can only be a new link.
+#[derive(Default)]
+pub struct Reader {
+    buffer: Vec<u8>,
+    serial: Option<u32>,
+    /// Whether the followed stream went past its first page, or ended.
+    started: bool,
+    ended: bool,
+    packet: Vec<u8>,
+    first: bool,
+}
+
+impl Reader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `data` and returns every packet it completes.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<Packet>> {
+        self.buffer.extend_from_slice(data);
+
+        let mut packets = Vec::new();
+        while let Some(size) = self.page_size()? {
+            let page: Vec<u8> = self.buffer.drain(..size).collect();
+            self.read_page(&page, &mut packets)?;
+        }
+
+        Ok(packets)
+    }
+
+    /// Size of the page at the start of the buffer, once all of it has arrived.
+    fn page_size(&self) -> Result<Option<usize>> {
+        let prefix = self.buffer.len().min(CAPTURE_PATTERN.len());
+        if self.buffer[..prefix] != CAPTURE_PATTERN[..prefix] {
+            return Err(Status::invalid_argument("not an Ogg stream").into());
+        }
+        if self.buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let segments = self.buffer[26] as usize;
+        if self.buffer.len() < HEADER_SIZE + segments {
+            return Ok(None);
+        }
+        let body: usize = self.buffer[HEADER_SIZE..HEADER_SIZE + segments]
+            .iter()
+            .map(|lacing| *lacing as usize)
+            .sum();
+        let size = HEADER_SIZE + segments + body;
+
+        Ok(if self.buffer.len() < size {
+            None
+        } else {
+            Some(size)
+        })
+    }
+
+    fn read_page(&mut self, page: &[u8], packets: &mut Vec<Packet>) -> Result<()> {
+        if page[4] != 0 {
+            return Err(Status::invalid_argument("unsupported Ogg version").into());
+        }
+
+        let mut unchecked = page.to_vec();
+        unchecked[22..26].fill(0);
+        if crc(&unchecked).to_le_bytes() != page[22..26] {
+            return Err(Status::invalid_argument("Ogg page checksum mismatch").into());
+        }
+
+        let flags = page[5];
+        let granule = u64::from_le_bytes(page[6..14].try_into().unwrap());
+        let serial = u32::from_le_bytes([page[14], page[15], page[16], page[17]]);
+        if flags & FLAG_BOS != 0 && (self.serial.is_none() || self.started || self.ended) {
+            self.serial = Some(serial);
+            self.started = false;
+            self.ended = false;
+            self.packet.clear();
+            self.first = true;
+        } else if self.serial == Some(serial) {
+            self.started = true;
+        }
+        if self.serial != Some(serial) || self.ended {
+            return Ok(());
+        }
+
+        // A page that does not continue a packet discards any unfinished one.
+        if flags & FLAG_CONTINUED == 0 {
+            self.packet.clear();
+        }
+
+        let segments = page[26] as usize;
+        let lacing = &page[HEADER_SIZE..HEADER_SIZE + segments];
+        let mut offset = HEADER_SIZE + segments;
+        let completed = packets.len();
+        for size in lacing.iter().map(|size| *size as usize) {
+            self.packet.extend_from_slice(&page[offset..offset + size]);
+            offset += size;
+
+            if size < 255 {
+                packets.push(Packet {
+                    data: std::mem::take(&mut self.packet),
+                    first: std::mem::take(&mut self.first),
+                    last: false,
+                    granule: None,
+                });
+            }
+        }
+
+        if let Some(packet) = packets[completed..].last_mut() {
+            if granule != NO_GRANULE {
+                packet.granule = Some(granule);
+            }
+            packet.last = flags & FLAG_EOS != 0;
+        }
+        if flags & FLAG_EOS != 0 {
+            self.ended = true;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Builds a page from raw lacing values and body, with a valid checksum.
+    pub fn page(serial: u32, flags: u8, granule: u64, lacing: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut page = CAPTURE_PATTERN.to_vec();
+        page.push(0);
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&serial.to_le_bytes());
+        page.extend_from_slice(&[0; 8]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(lacing);
+        page.extend_from_slice(body);
+
+        let crc = crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    /// Builds a page holding whole `packets` of fewer than 255 bytes each.
+    pub fn packets_page(serial: u32, flags: u8, granule: u64, packets: &[&[u8]]) -> Vec<u8> {
+        let lacing: Vec<u8> = packets.iter().map(|packet| packet.len() as u8).collect();
+        page(serial, flags, granule, &lacing, &packets.concat())
+    }
+
+    fn read(reader: &mut Reader, data: &[u8]) -> Vec<Vec<u8>> {
+        reader
+            .push(data)
+            .unwrap()
+            .into_iter()
+            .map(|packet| packet.data)
+            .collect()
+    }
+
+    #[test]
+    fn crc_uses_the_ogg_polynomial() {
+        assert_eq!(crc(b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn packets_are_reassembled_across_pages_and_pushes() {
+        let long: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut data = page(1, FLAG_BOS, NO_GRANULE, &[255], &long[..255]);
+        let mut body = long[255..].to_vec();
+        body.extend_from_slice(b"end");
+        data.extend(page(1, FLAG_CONTINUED, 1000, &[45, 3], &body));
+
+        let mut reader = Reader::new();
+        let mut packets = Vec::new();
+        for byte in data.iter() {
+            packets.extend(reader.push(&[*byte]).unwrap());
+        }
+
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].data, long);
+        assert!(packets[0].first);
+        assert_eq!(packets[0].granule, None);
+        assert_eq!(packets[1].data, b"end");
+        assert!(!packets[1].first);
+        assert_eq!(packets[1].granule, Some(1000));
+    }
+
+    #[test]
+    fn corrupt_pages_are_rejected() {
+        let mut data = packets_page(1, FLAG_BOS, 0, &[b"data"]);
+        let last = data.len() - 1;
+        data[last] ^= 1;
+
+        assert!(Reader::new().push(&data).is_err());
+        assert!(Reader::new().push(b"RIFF").is_err());
+    }
+
+    #[test]
+    fn other_streams_are_skipped_and_chained_links_followed() {
+        let mut reader = Reader::new();
+        let mut data = packets_page(1, FLAG_BOS, 0, &[b"a"]);
+        data.extend(packets_page(2, FLAG_BOS, 0, &[b"x"]));
+        data.extend(packets_page(2, 0, 10, &[b"y"]));
+        data.extend(packets_page(1, FLAG_EOS, 10, &[b"b"]));
+        data.extend(packets_page(3, FLAG_BOS, 0, &[b"c"]));
+        let packets = reader.push(&data).unwrap();
+
+        let summary: Vec<(&[u8], bool, bool)> = packets
+            .iter()
+            .map(|packet| (packet.data.as_slice(), packet.first, packet.last))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (&b"a"[..], true, false),
+                (&b"b"[..], false, true),
+                (&b"c"[..], true, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn a_new_link_may_begin_without_the_previous_one_ending() {
+        let mut reader = Reader::new();
+        let mut data = packets_page(1, FLAG_BOS, 0, &[b"a"]);
+        data.extend(packets_page(1, 0, 10, &[b"b"]));
+        data.extend(packets_page(2, FLAG_BOS, 0, &[b"c"]));
+
+        assert_eq!(read(&mut reader, &data), vec![b"a", b"b", b"c"]);
+    }
+}
@@ -0,0 +1,904 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+use tonic::Status;
+
+use crate::coder::{self, FRAME_SIZE, PACKET_SIZE, SAMPLE_RATE};
+use crate::config::Config;
+use crate::error::Result;
+use crate::mixer::{self, Limiter};
+
+/// Number of frames that should have been released `elapsed` after the clock started.
+fn frames_due(elapsed: Duration) -> u64 {
+    (elapsed.as_nanos() * SAMPLE_RATE as u128 / (FRAME_SIZE as u128 * 1_000_000_000)) as u64 + 1
+}
+
+/// How long a stream may go without audio before it stops preempting lower priorities.
+const PRIORITY_HOLD: Duration = Duration::from_millis(250);
+
+/// Number of whole frames needed to hold `duration` of audio.
+pub fn frames_for(duration: Duration) -> usize {
+    (duration.as_nanos() * SAMPLE_RATE as u128 / (FRAME_SIZE as u128 * 1_000_000_000)) as usize
+}
+
+/// Playback duration of `frames` frames.
+pub fn frames_duration(frames: u64) -> Duration {
+    Duration::from_nanos(frames * FRAME_SIZE as u64 * 1_000_000_000 / SAMPLE_RATE as u64)
+}
+
+/// What an active stream does to lower-priority streams on the same target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Preemption {
+    /// Keep mixing them in at a reduced gain.
+    Duck,
+    /// Hold their audio back until the stream goes quiet.
+    Pause,
+    /// Drop them altogether.
+    Cancel,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub priority: i32,
+    pub preemption: Preemption,
+    /// Whether the stream may speak as a real player rather than only as the extension's
+    /// bots.
+    pub impersonate: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            preemption: Preemption::Duck,
+            impersonate: false,
+        }
+    }
+}
+
+/// Player an injection speaks as, resolved to a client slot on the game thread.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// 0-based client slot, or -1 for the extension's default bot.
+    Slot(i32),
+    SteamId(u64),
+    /// Fake client created by the extension under this name.
+    Bot(String),
+}
+
+/// Client slot an `Address` currently refers to.
+#[derive(Clone, Copy, Debug)]
+pub struct Resolved {
+    pub client_index: i32,
+    /// Whether the slot belongs to anyone but a bot the extension created.
+    pub real_player: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Contributed to the most recent frame of its target.
+    Playing,
+    /// Waiting for audio, or held back by a higher-priority stream.
+    Waiting,
+    /// Paused through the management API.
+    Paused,
+}
+
+/// Snapshot of a stream for the management API.
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub id: u64,
+    pub address: Address,
+    pub origin: String,
+    pub options: Options,
+    pub state: State,
+    pub queued: Duration,
+    pub played: Duration,
+}
+
+#[derive(Default)]
+struct Queue {
+    frames: VecDeque<Vec<i16>>,
+    finished: bool,
+    failure: Option<Status>,
+    /// Whether the target has been resolved to a slot the stream may speak through.
+    resolved: bool,
+    paused: bool,
+    /// Playback time of the first frame the stream had nothing for, since it last played.
+    idle_since: Option<Instant>,
+    played_frames: u64,
+    gain: f32,
+}
+
+/// PCM frames queued by a single `SendVoiceData` stream.
+pub struct Stream {
+    id: u64,
+    origin: String,
+    options: Options,
+    queue: Mutex<Queue>,
+    drained: Notify,
+}
+
+impl Stream {
+    /// Takes the frame played at `at` along with the gain the stream is mixed in at.
+    fn pop(&self, at: Instant) -> Option<(Vec<i16>, f32)> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.paused {
+            return None;
+        }
+
+        let frame = queue.frames.pop_front();
+        if frame.is_some() {
+            queue.idle_since = None;
+            queue.played_frames += 1;
+            self.drained.notify_one();
+        } else {
+            queue.idle_since.get_or_insert(at);
+        }
+
+        frame.map(|frame| (frame, queue.gain))
+    }
+
+    fn hold(&self, at: Instant) {
+        self.queue.lock().unwrap().idle_since.get_or_insert(at);
+    }
+
+    /// Drops everything that has not been played yet and ends the stream with `status`.
+    fn cancel(&self, status: Status) {
+        let mut queue = self.queue.lock().unwrap();
+        if !queue.finished || !queue.frames.is_empty() {
+            queue.finished = true;
+            queue.failure = Some(status);
+        }
+        queue.frames.clear();
+        self.drained.notify_one();
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.queue.lock().unwrap().paused = paused;
+    }
+
+    /// Whether the stream is playing at `at`, or paused only briefly between chunks.
+    fn is_active(&self, at: Instant) -> bool {
+        let queue = self.queue.lock().unwrap();
+        let holding = match queue.idle_since {
+            Some(since) => at.saturating_duration_since(since) < PRIORITY_HOLD,
+            None => true,
+        };
+
+        !queue.paused && (!queue.frames.is_empty() || (!queue.finished && holding))
+    }
+
+    fn info(&self, address: &Address) -> Info {
+        let queue = self.queue.lock().unwrap();
+        let state = if queue.paused {
+            State::Paused
+        } else if queue.idle_since.is_none() && queue.played_frames > 0 {
+            State::Playing
+        } else {
+            State::Waiting
+        };
+
+        Info {
+            id: self.id,
+            address: address.clone(),
+            origin: self.origin.clone(),
+            options: self.options,
+            state,
+            queued: frames_duration(queue.frames.len() as u64),
+            played: frames_duration(queue.played_frames),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        let queue = self.queue.lock().unwrap();
+        queue.finished && queue.frames.is_empty()
+    }
+}
+
+/// Producer side of a `Stream`, owned by the task reading the gRPC stream.
+///
+/// A writer that is dropped before `finish` or `end` is called cancels its stream, so
+/// audio from an uploader that failed or went away is not played.
+pub struct StreamWriter {
+    stream: Arc<Stream>,
+}
+
+impl StreamWriter {
+    pub fn id(&self) -> u64 {
+        self.stream.id
+    }
+
+    /// Ends the stream normally; whatever is still queued keeps playing.
+    pub fn finish(self) {
+        self.end();
+    }
+
+    /// Ends the stream like `finish`, but keeps the writer to watch the rest play out.
+    pub fn end(&self) {
+        self.stream.queue.lock().unwrap().finished = true;
+    }
+
+    /// Drops everything that has not been played yet and ends the stream with `status`.
+    pub fn cancel(&self, status: Status) {
+        self.stream.cancel(status);
+    }
+
+    /// Drops the frames queued so far without ending the stream.
+    pub fn clear(&self) {
+        self.stream.queue.lock().unwrap().frames.clear();
+        self.stream.drained.notify_one();
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.stream.set_paused(paused);
+    }
+
+    /// Sets the gain the stream is mixed in at, 1.0 being unchanged.
+    pub fn set_gain(&self, gain: f32) {
+        self.stream.queue.lock().unwrap().gain = gain;
+    }
+
+    /// How much of the stream has been played so far.
+    pub fn played(&self) -> Duration {
+        frames_duration(self.stream.queue.lock().unwrap().played_frames)
+    }
+
+    /// Queues PCM frames, returning the number of frames now waiting to be played.
+    pub fn push(&self, frames: Vec<Vec<i16>>) -> usize {
+        let mut queue = self.stream.queue.lock().unwrap();
+        queue.frames.extend(frames);
+
+        queue.frames.len()
+    }
+
+    /// Number of frames waiting to be played.
+    pub fn queued(&self) -> usize {
+        self.stream.queue.lock().unwrap().frames.len()
+    }
+
+    /// Why the stream was ended early, if it was: cancelled by a higher-priority stream or
+    /// through the management API, or its target could not be resolved.
+    pub fn take_failure(&self) -> Option<Status> {
+        self.stream.queue.lock().unwrap().failure.take()
+    }
+
+    /// Waits until fewer than `limit` frames are waiting to be played.
+    pub async fn wait_below(&self, limit: usize) {
+        loop {
+            if self.stream.queue.lock().unwrap().frames.len() < limit {
+                return;
+            }
+            self.stream.drained.notified().await;
+        }
+    }
+
+    /// Waits until the game thread has found a slot for the stream's target at least once,
+    /// or has ended the stream because it could not.
+    pub async fn wait_resolved(&self) {
+        loop {
+            {
+                let queue = self.stream.queue.lock().unwrap();
+                if queue.resolved || queue.failure.is_some() || queue.finished {
+                    return;
+                }
+            }
+            self.stream.drained.notified().await;
+        }
+    }
+
+    /// Waits until an ended stream has played everything it was given, or was cut short.
+    pub async fn wait_done(&self) -> Result<(), Status> {
+        loop {
+            {
+                let mut queue = self.stream.queue.lock().unwrap();
+                if queue.finished && queue.frames.is_empty() {
+                    return match queue.failure.take() {
+                        Some(status) => Err(status),
+                        None => Ok(()),
+                    };
+                }
+            }
+            self.stream.drained.notified().await;
+        }
+    }
+}
+
+impl Drop for StreamWriter {
+    fn drop(&mut self) {
+        if !self.stream.queue.lock().unwrap().finished {
+            self.stream.cancel(Status::cancelled("uploader went away"));
+        }
+    }
+}
+
+struct Clock {
+    start: Instant,
+    released: u64,
+}
+
+struct Channel {
+    streams: Vec<Arc<Stream>>,
+    clock: Option<Clock>,
+    limiter: Limiter,
+    encoder: coder::Encoder,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            streams: Vec::new(),
+            clock: None,
+            limiter: Limiter::new(),
+            encoder: coder::Encoder::new(),
+        }
+    }
+}
+
+impl Channel {
+    /// Strongest preemption that active higher-priority streams apply to `stream`.
+    fn preemption_of(&self, stream: &Stream, active: &[bool]) -> Option<Preemption> {
+        self.streams
+            .iter()
+            .zip(active.iter())
+            .filter(|(other, active)| **active && other.options.priority > stream.options.priority)
+            .map(|(other, _)| other.options.preemption)
+            .max()
+    }
+
+    /// Mixes the frame played at `at` from every stream that has one queued and encodes
+    /// the result.
+    fn next_frame(&mut self, at: Instant, duck_gain: f32) -> Option<Vec<u8>> {
+        let active: Vec<bool> = self
+            .streams
+            .iter()
+            .map(|stream| stream.is_active(at))
+            .collect();
+
+        let mut mix = vec![0; FRAME_SIZE];
+        let mut sources = 0;
+        for stream in self.streams.iter() {
+            let gain = match self.preemption_of(stream, &active) {
+                None => 1.0,
+                Some(Preemption::Duck) => duck_gain,
+                Some(Preemption::Pause) => {
+                    stream.hold(at);
+                    continue;
+                }
+                Some(Preemption::Cancel) => {
+                    stream.cancel(Status::aborted("preempted by a higher-priority stream"));
+                    continue;
+                }
+            };
+
+            if let Some((frame, stream_gain)) = stream.pop(at) {
+                mixer::accumulate(&mut mix, &frame, gain * stream_gain);
+                sources += 1;
+            }
+        }
+        if sources == 0 {
+            return None;
+        }
+
+        let pcm = self.limiter.process(&mix);
+        let mut data = vec![0; PACKET_SIZE];
+        if let Err(err) = self.encoder.encode(&pcm, &mut data) {
+            crate::ffi::log_error(&format!("encode error: {}", err));
+        }
+
+        Some(data)
+    }
+}
+
+/// Mixes and releases the voice frames queued for each injection target at the codec's
+/// real-time rate.
+///
+/// Streams are grouped by the client slot their address resolves to on each tick, so
+/// addresses naming the same player (a slot, its SteamID or a bot name) share one
+/// channel. All streams of a channel are summed and encoded once per frame with the
+/// slot's own encoder, so concurrent streams play together instead of garbling each
+/// other's codec state. While a stream is active, streams of lower priority on the same
+/// slot are ducked, paused or cancelled according to its `Preemption`.
+///
+/// Every channel keeps its own clock anchored at the first frame it released, so the
+/// cadence does not depend on the server tick rate: a tick releases however many frames
+/// became due since the previous one. The clock is dropped once the channel has played
+/// everything it was given, and re-anchored when new audio arrives.
+pub struct Scheduler {
+    targets: HashMap<Address, Vec<Arc<Stream>>>,
+    channels: HashMap<i32, Channel>,
+    next_id: u64,
+    duck_gain: f32,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            targets: HashMap::new(),
+            channels: HashMap::new(),
+            next_id: 1,
+            duck_gain: Config::default().duck_gain,
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn configure(&mut self, config: &Config) {
+        self.duck_gain = config.duck_gain;
+    }
+
+    /// Registers a new stream for `address` and returns its producer handle.
+    pub fn open(&mut self, address: Address, origin: String, options: Options) -> StreamWriter {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let stream = Arc::new(Stream {
+            id,
+            origin,
+            options,
+            queue: Mutex::new(Queue {
+                gain: 1.0,
+                ..Queue::default()
+            }),
+            drained: Notify::new(),
+        });
+        self.targets
+            .entry(address)
+            .or_default()
+            .push(stream.clone());
+
+        StreamWriter { stream }
+    }
+
+    pub fn list(&self) -> Vec<Info> {
+        let mut list: Vec<Info> = self
+            .targets
+            .iter()
+            .flat_map(|(address, streams)| streams.iter().map(move |stream| stream.info(address)))
+            .collect();
+        list.sort_by_key(|info| info.id);
+
+        list
+    }
+
+    fn find(&self, id: u64) -> Option<&Arc<Stream>> {
+        self.targets
+            .values()
+            .flatten()
+            .find(|stream| stream.id == id)
+    }
+
+    /// Cancels the stream with `id`, returning whether it exists.
+    pub fn cancel(&self, id: u64) -> bool {
+        self.find(id)
+            .map(|stream| stream.cancel(Status::cancelled("injection was cancelled")))
+            .is_some()
+    }
+
+    /// Pauses or resumes the stream with `id`, returning whether it exists.
+    pub fn set_paused(&self, id: u64, paused: bool) -> bool {
+        self.find(id)
+            .map(|stream| stream.set_paused(paused))
+            .is_some()
+    }
+
+    /// Sets the gain of the stream with `id`, returning whether it exists.
+    pub fn set_gain(&self, id: u64, gain: f32) -> bool {
+        self.find(id)
+            .map(|stream| stream.queue.lock().unwrap().gain = gain)
+            .is_some()
+    }
+
+    /// Collects the frames that are due at `now`, concatenated per client slot.
+    ///
+    /// `resolve` maps each target to the slot it currently speaks through; streams of a
+    /// target that cannot be resolved are ended with the returned status, and streams
+    /// without permission to impersonate are refused when the slot is a real player.
+    pub fn tick<F>(&mut self, now: Instant, mut resolve: F) -> Vec<(i32, Vec<u8>)>
+    where
+        F: FnMut(&Address) -> Result<Resolved>,
+    {
+        for channel in self.channels.values_mut() {
+            channel.streams.clear();
+        }
+
+        for (address, streams) in self.targets.iter_mut() {
+            let resolved = match resolve(address) {
+                Ok(resolved) => resolved,
+                Err(err) => {
+                    let status = err.status();
+                    for stream in streams.drain(..) {
+                        stream.cancel(Status::new(status.code(), status.message()));
+                    }
+                    continue;
+                }
+            };
+
+            if resolved.real_player {
+                streams.retain(|stream| {
+                    if stream.options.impersonate {
+                        return true;
+                    }
+
+                    stream.cancel(Status::permission_denied(
+                        "injection target is a real player",
+                    ));
+                    false
+                });
+            }
+
+            for stream in streams.iter() {
+                let mut queue = stream.queue.lock().unwrap();
+                if !queue.resolved {
+                    queue.resolved = true;
+                    stream.drained.notify_one();
+                }
+            }
+            self.channels
+                .entry(resolved.client_index)
+                .or_default()
+                .streams
+                .extend(streams.iter().cloned());
+        }
+
+        let mut packets = Vec::new();
+        for (client_index, channel) in self.channels.iter_mut() {
+            // Mix in the order the streams were opened, whichever address they came through.
+            channel.streams.sort_by_key(|stream| stream.id);
+
+            let mut data = Vec::new();
+
+            let mut clock = channel.clock.take().unwrap_or(Clock {
+                start: now,
+                released: 0,
+            });
+            let due = frames_due(now.saturating_duration_since(clock.start));
+            let mut released = 0;
+            while clock.released < due {
+                let at = clock.start + frames_duration(clock.released);
+                let frame = match channel.next_frame(at, self.duck_gain) {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                data.extend_from_slice(&frame);
+                clock.released += 1;
+                released += 1;
+            }
+
+            // The target ran dry: if nothing went out the previous audio has finished playing
+            // and the next frame starts a fresh clock, otherwise the clock is re-anchored so
+            // the next frame follows the ones just released.
+            channel.clock = if clock.released >= due {
+                Some(clock)
+            } else if released > 0 {
+                Some(Clock {
+                    start: now,
+                    released,
+                })
+            } else {
+                None
+            };
+
+            if !data.is_empty() {
+                packets.push((*client_index, data));
+            }
+        }
+
+        for streams in self.targets.values_mut() {
+            streams.retain(|stream| !stream.is_done());
+        }
+        self.targets.retain(|_, streams| !streams.is_empty());
+        self.channels.retain(|_, channel| {
+            channel.streams.retain(|stream| !stream.is_done());
+            !channel.streams.is_empty() || channel.clock.is_some()
+        });
+
+        packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn frames(count: usize) -> Vec<Vec<i16>> {
+        vec![vec![1000; FRAME_SIZE]; count]
+    }
+
+    /// Frames released per client slot by a tick at `now`.
+    fn released(scheduler: &mut Scheduler, now: Instant) -> Vec<(i32, usize)> {
+        // Every bot lives in slot 1.
+        let resolve = |address: &Address| match address {
+            Address::Slot(client_index) => Ok(Resolved {
+                client_index: *client_index,
+                real_player: false,
+            }),
+            Address::Bot(_) => Ok(Resolved {
+                client_index: 1,
+                real_player: false,
+            }),
+            Address::SteamId(_) => Err(Status::not_found("no such player").into()),
+        };
+
+        scheduler
+            .tick(now, resolve)
+            .into_iter()
+            .map(|(client_index, data)| (client_index, data.len() / PACKET_SIZE))
+            .collect()
+    }
+
+    #[test]
+    fn frames_due_counts_the_frame_at_the_start_of_the_clock() {
+        // A frame of 512 samples at 22050 Hz lasts about 23.2 ms.
+        assert_eq!(frames_due(Duration::ZERO), 1);
+        assert_eq!(frames_due(Duration::from_millis(23)), 1);
+        assert_eq!(frames_due(Duration::from_millis(24)), 2);
+        assert_eq!(frames_due(Duration::from_secs(1)), 44);
+    }
+
+    #[test]
+    fn frames_are_released_at_the_codec_rate() {
+        let mut scheduler = Scheduler::new();
+        let writer = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        writer.push(frames(10));
+
+        let start = Instant::now();
+        assert_eq!(released(&mut scheduler, start), vec![(1, 1)]);
+        assert!(released(&mut scheduler, start + Duration::from_millis(10)).is_empty());
+        assert_eq!(
+            released(&mut scheduler, start + Duration::from_millis(100)),
+            vec![(1, 4)]
+        );
+        assert_eq!(writer.queued(), 5);
+    }
+
+    #[test]
+    fn streams_on_one_target_are_mixed_into_one_frame() {
+        let mut scheduler = Scheduler::new();
+        let first = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        let second = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        let other = scheduler.open(Address::Slot(2), String::new(), Options::default());
+        first.push(frames(2));
+        second.push(frames(1));
+        other.push(frames(1));
+
+        let start = Instant::now();
+        let mut packets = released(&mut scheduler, start);
+        packets.sort_unstable();
+        assert_eq!(packets, vec![(1, 1), (2, 1)]);
+        assert_eq!((first.queued(), second.queued()), (1, 0));
+
+        // The longer stream keeps playing on its own.
+        assert_eq!(
+            released(&mut scheduler, start + Duration::from_millis(24)),
+            vec![(1, 1)]
+        );
+        assert_eq!(first.queued(), 0);
+    }
+
+    #[test]
+    fn addresses_of_one_slot_share_a_channel() {
+        let mut scheduler = Scheduler::new();
+        let slot = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        let bot = scheduler.open(
+            Address::Bot("bot".into()),
+            String::new(),
+            Options::default(),
+        );
+        slot.push(frames(1));
+        bot.push(frames(1));
+
+        assert_eq!(released(&mut scheduler, Instant::now()), vec![(1, 1)]);
+        assert_eq!((slot.queued(), bot.queued()), (0, 0));
+    }
+
+    #[test]
+    fn unresolved_targets_end_their_streams() {
+        let mut scheduler = Scheduler::new();
+        let writer = scheduler.open(Address::SteamId(1), String::new(), Options::default());
+        writer.push(frames(1));
+
+        assert!(released(&mut scheduler, Instant::now()).is_empty());
+        assert_eq!(
+            writer.take_failure().map(|status| status.code()),
+            Some(tonic::Code::NotFound)
+        );
+        assert!(scheduler.list().is_empty());
+    }
+
+    #[test]
+    fn wait_resolved_returns_once_the_target_was_looked_at() {
+        let mut scheduler = Scheduler::new();
+        let found = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        let missing = scheduler.open(Address::SteamId(1), String::new(), Options::default());
+        found.push(frames(1));
+        missing.push(frames(1));
+        assert!(found.wait_resolved().now_or_never().is_none());
+        assert!(missing.wait_resolved().now_or_never().is_none());
+
+        released(&mut scheduler, Instant::now());
+        assert!(found.wait_resolved().now_or_never().is_some());
+        assert!(found.take_failure().is_none());
+        assert!(missing.wait_resolved().now_or_never().is_some());
+        assert_eq!(
+            missing.take_failure().map(|status| status.code()),
+            Some(tonic::Code::NotFound)
+        );
+    }
+
+    #[test]
+    fn real_players_refuse_streams_that_may_not_impersonate() {
+        let mut scheduler = Scheduler::new();
+        let refused = scheduler.open(Address::Slot(2), String::new(), Options::default());
+        let allowed = scheduler.open(
+            Address::Slot(2),
+            String::new(),
+            Options {
+                impersonate: true,
+                ..Options::default()
+            },
+        );
+        refused.push(frames(1));
+        allowed.push(frames(1));
+
+        let packets = scheduler.tick(Instant::now(), |_| {
+            Ok(Resolved {
+                client_index: 2,
+                real_player: true,
+            })
+        });
+        assert_eq!(packets.len(), 1);
+        assert!(refused.wait_resolved().now_or_never().is_some());
+        assert_eq!(
+            refused.take_failure().map(|status| status.code()),
+            Some(tonic::Code::PermissionDenied)
+        );
+        assert!(allowed.take_failure().is_none());
+    }
+
+    #[test]
+    fn finished_streams_play_out_their_queue() {
+        let mut scheduler = Scheduler::new();
+        let writer = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        writer.push(frames(3));
+        let id = writer.id();
+        writer.finish();
+
+        let start = Instant::now();
+        assert_eq!(released(&mut scheduler, start), vec![(1, 1)]);
+        assert_eq!(
+            released(&mut scheduler, start + Duration::from_millis(50)),
+            vec![(1, 2)]
+        );
+        assert!(scheduler.list().iter().all(|info| info.id != id));
+    }
+
+    #[test]
+    fn dropped_writers_cancel_their_stream() {
+        let mut scheduler = Scheduler::new();
+        let writer = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        writer.push(frames(3));
+        drop(writer);
+
+        assert!(released(&mut scheduler, Instant::now()).is_empty());
+        assert!(scheduler.list().is_empty());
+    }
+
+    fn preempting(preemption: Preemption) -> Options {
+        Options {
+            priority: 1,
+            preemption,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn ducked_streams_keep_playing() {
+        let mut scheduler = Scheduler::new();
+        let low = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        let high = scheduler.open(
+            Address::Slot(1),
+            String::new(),
+            preempting(Preemption::Duck),
+        );
+        low.push(frames(2));
+        high.push(frames(2));
+
+        assert_eq!(released(&mut scheduler, Instant::now()), vec![(1, 1)]);
+        assert_eq!((low.queued(), high.queued()), (1, 1));
+    }
+
+    #[test]
+    fn paused_streams_resume_once_the_preempting_stream_ends() {
+        let mut scheduler = Scheduler::new();
+        let low = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        let high = scheduler.open(
+            Address::Slot(1),
+            String::new(),
+            preempting(Preemption::Pause),
+        );
+        low.push(frames(2));
+        high.push(frames(1));
+        high.finish();
+
+        let start = Instant::now();
+        assert_eq!(released(&mut scheduler, start), vec![(1, 1)]);
+        assert_eq!(low.queued(), 2);
+
+        assert_eq!(
+            released(&mut scheduler, start + Duration::from_millis(24)),
+            vec![(1, 1)]
+        );
+        assert_eq!(low.queued(), 1);
+    }
+
+    #[test]
+    fn cancelled_streams_report_the_preemption() {
+        let mut scheduler = Scheduler::new();
+        let low = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        let high = scheduler.open(
+            Address::Slot(1),
+            String::new(),
+            preempting(Preemption::Cancel),
+        );
+        low.push(frames(2));
+        high.push(frames(1));
+
+        released(&mut scheduler, Instant::now());
+        assert_eq!(low.queued(), 0);
+        assert_eq!(
+            low.take_failure().map(|status| status.code()),
+            Some(tonic::Code::Aborted)
+        );
+    }
+
+    #[test]
+    fn a_quiet_stream_stops_preempting_after_the_hold_time() {
+        let mut scheduler = Scheduler::new();
+        let low = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        let _high = scheduler.open(
+            Address::Slot(1),
+            String::new(),
+            preempting(Preemption::Pause),
+        );
+        low.push(frames(1));
+
+        // The hold follows the clock, however often the game ticks in between.
+        let start = Instant::now();
+        for millis in 0..10 {
+            assert!(released(&mut scheduler, start + Duration::from_millis(millis)).is_empty());
+        }
+        assert!(released(&mut scheduler, start + PRIORITY_HOLD / 2).is_empty());
+        assert_eq!(
+            released(&mut scheduler, start + PRIORITY_HOLD),
+            vec![(1, 1)]
+        );
+    }
+
+    #[test]
+    fn clock_restarts_after_the_target_runs_dry() {
+        let mut scheduler = Scheduler::new();
+        let writer = scheduler.open(Address::Slot(1), String::new(), Options::default());
+        writer.push(frames(1));
+
+        let start = Instant::now();
+        assert_eq!(released(&mut scheduler, start), vec![(1, 1)]);
+        assert!(released(&mut scheduler, start + frames_duration(1)).is_empty());
+        assert!(released(&mut scheduler, start + frames_duration(2)).is_empty());
+
+        // Audio arriving after a gap is not released in a burst to catch up.
+        writer.push(frames(5));
+        assert_eq!(
+            released(&mut scheduler, start + frames_duration(10)),
+            vec![(1, 1)]
+        );
+    }
+}
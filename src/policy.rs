@@ -0,0 +1,34 @@
+use tonic::metadata::MetadataMap;
+
+use crate::config::Config;
+
+/// Whether the caller behind `metadata` may make real players speak.
+///
+/// The extension's own bots are always fair game. Every other client, game bots included,
+/// needs either `VoiceServerAllowImpersonation` or an `authorization: Bearer <token>`
+/// header matching `VoiceServerImpersonationToken`.
+pub fn may_impersonate(config: &Config, metadata: &MetadataMap) -> bool {
+    if config.allow_impersonation {
+        return true;
+    }
+    if config.impersonation_token.is_empty() {
+        return false;
+    }
+
+    let token = metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) => constant_time_eq(token.as_bytes(), config.impersonation_token.as_bytes()),
+        None => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -0,0 +1,899 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::Stream;
+use tonic::Status;
+
+use crate::coder::{self, OpusEncoder};
+use crate::error::Result;
+use crate::ffi;
+use crate::resample::{self, Resampler};
+use crate::utterance::{Event, Segmenter};
+use crate::voiceserver::{
+    AudioEncoding, Lagged, RecvVoiceRequest, RecvVoiceResponse, Speaker, TalkEvent, TalkEventKind,
+};
+
+/// Messages a subscription buffers for a slow reader unless it asks for another depth.
+const DEFAULT_BUFFER_DEPTH: usize = 10;
+/// Deepest buffer a subscription may ask for.
+const MAX_BUFFER_DEPTH: usize = 1000;
+/// Time without packets after which a speaker's talk spurt counts as over.
+const SPURT_GAP: Duration = Duration::from_millis(200);
+
+/// Receiving end of a `RecvVoiceData` stream.
+///
+/// Messages already queued are delivered first; a disconnected subscriber's stream then
+/// ends with the reason, without waiting for room in the queue.
+pub struct RecvVoiceReceiver {
+    messages: mpsc::Receiver<Result<RecvVoiceResponse, Status>>,
+    disconnect: Option<oneshot::Receiver<Status>>,
+}
+
+impl Stream for RecvVoiceReceiver {
+    type Item = Result<RecvVoiceResponse, Status>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Poll::Ready(Some(message)) = this.messages.poll_recv(cx) {
+            return Poll::Ready(Some(message));
+        }
+
+        if let Some(disconnect) = &mut this.disconnect {
+            if let Poll::Ready(status) = Pin::new(disconnect).poll(cx) {
+                this.disconnect = None;
+                this.messages.close();
+                if let Ok(status) = status {
+                    return Poll::Ready(Some(Err(status)));
+                }
+            }
+        }
+        this.messages.poll_recv(cx)
+    }
+}
+
+/// Which speakers a `RecvVoiceData` subscription wants to hear.
+pub struct Filter {
+    steamids: HashSet<u64>,
+    exclude_steamids: HashSet<u64>,
+    client_indexes: HashSet<i32>,
+    team: i32,
+    exclude_bots: bool,
+    min_level: u32,
+}
+
+impl Filter {
+    pub fn new(request: &RecvVoiceRequest) -> Self {
+        Self {
+            steamids: request.steamids.iter().copied().collect(),
+            exclude_steamids: request.exclude_steamids.iter().copied().collect(),
+            client_indexes: request.client_indexes.iter().copied().collect(),
+            team: request.team,
+            exclude_bots: request.exclude_bots,
+            // A level threshold would cut holes into a continuous timeline.
+            min_level: if request.continuous {
+                0
+            } else {
+                request.min_level
+            },
+        }
+    }
+
+    /// Whether a packet from `speaker` with RMS `level` passes the filter.
+    pub fn matches(&self, speaker: &Speaker, level: u32) -> bool {
+        self.matches_speaker(speaker) && level >= self.min_level
+    }
+
+    /// Whether `speaker` passes the filter, whatever the level of their audio.
+    pub fn matches_speaker(&self, speaker: &Speaker) -> bool {
+        (self.steamids.is_empty() || self.steamids.contains(&speaker.steamid))
+            && !self.exclude_steamids.contains(&speaker.steamid)
+            && (self.client_indexes.is_empty()
+                || self.client_indexes.contains(&speaker.client_index))
+            && (self.team == 0 || self.team == speaker.team)
+            && !(self.exclude_bots && speaker.bot)
+    }
+}
+
+/// Encoding a `RecvVoiceData` subscription receives voice in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    Pcm(u32),
+    Celt,
+    Opus,
+}
+
+impl Format {
+    pub fn new(request: &RecvVoiceRequest) -> Result<Self> {
+        // Concealed and silent frames have no CELT packet to pass through.
+        if request.continuous && request.encoding() == AudioEncoding::Celt {
+            return Err(Status::invalid_argument(
+                "continuous subscriptions need PCM or Opus output",
+            )
+            .into());
+        }
+
+        Self::from_encoding(request.encoding(), request.sample_rate)
+    }
+
+    /// Format for `encoding`, with `sample_rate` applying to PCM and 0 meaning 22050 Hz.
+    pub fn from_encoding(encoding: AudioEncoding, sample_rate: u32) -> Result<Self> {
+        match encoding {
+            AudioEncoding::PcmS16le => match sample_rate {
+                0 => Ok(Format::Pcm(coder::SAMPLE_RATE)),
+                rate @ resample::MIN_SAMPLE_RATE..=resample::MAX_SAMPLE_RATE => {
+                    Ok(Format::Pcm(rate))
+                }
+                rate => Err(Status::invalid_argument(format!(
+                    "sample rate {} is outside {}..={}",
+                    rate,
+                    resample::MIN_SAMPLE_RATE,
+                    resample::MAX_SAMPLE_RATE
+                ))
+                .into()),
+            },
+            AudioEncoding::Celt => Ok(Format::Celt),
+            AudioEncoding::Opus => Ok(Format::Opus),
+        }
+    }
+
+    pub fn encoding(&self) -> AudioEncoding {
+        match self {
+            Format::Pcm(_) => AudioEncoding::PcmS16le,
+            Format::Celt => AudioEncoding::Celt,
+            Format::Opus => AudioEncoding::Opus,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        match self {
+            Format::Pcm(rate) => *rate,
+            Format::Celt => coder::SAMPLE_RATE,
+            Format::Opus => coder::OPUS_SAMPLE_RATE,
+        }
+    }
+}
+
+/// A voice packet converted to one subscription format.
+#[derive(Clone, Default)]
+pub struct Output {
+    pub audio_data: Vec<u8>,
+    pub opus_packets: Vec<Vec<u8>>,
+}
+
+impl Output {
+    pub fn is_empty(&self) -> bool {
+        self.audio_data.is_empty() && self.opus_packets.is_empty()
+    }
+}
+
+/// Conversion state for one speaker in one format.
+///
+/// Resampling and Opus framing carry samples across packets, so the state lives as
+/// long as the speaker keeps talking and belongs to whoever receives exactly the packets
+/// it was fed. CELT is passed through by `Transcoders` and never reaches a transcoder.
+pub struct Transcoder {
+    resampler: Resampler,
+    opus: Option<(OpusEncoder, Vec<i16>)>,
+}
+
+impl Transcoder {
+    pub fn new(format: Format) -> Self {
+        let opus = match format {
+            Format::Opus => Some((OpusEncoder::new(), Vec::new())),
+            _ => None,
+        };
+
+        Self {
+            resampler: Resampler::new(coder::SAMPLE_RATE, format.sample_rate()),
+            opus,
+        }
+    }
+
+    pub fn process(&mut self, pcm: &[i16]) -> Output {
+        let pcm = self.resampler.process(pcm);
+        self.output(pcm, false)
+    }
+
+    /// Flushes the resampler and pads the last Opus frame, once the audio has ended.
+    pub fn finish(&mut self) -> Output {
+        let pcm = self.resampler.flush();
+        self.output(pcm, true)
+    }
+
+    fn output(&mut self, pcm: Vec<i16>, pad: bool) -> Output {
+        let (encoder, pending) = match &mut self.opus {
+            Some(opus) => opus,
+            None => {
+                return Output {
+                    audio_data: pcm.iter().flat_map(|sample| sample.to_le_bytes()).collect(),
+                    opus_packets: Vec::new(),
+                }
+            }
+        };
+
+        pending.extend_from_slice(&pcm);
+        if pad && pending.len() % coder::OPUS_FRAME_SIZE != 0 {
+            let padded = (pending.len() / coder::OPUS_FRAME_SIZE + 1) * coder::OPUS_FRAME_SIZE;
+            pending.resize(padded, 0);
+        }
+        let frames = pending.len() / coder::OPUS_FRAME_SIZE;
+        let mut opus_packets = Vec::with_capacity(frames);
+        for frame in pending.chunks_exact(coder::OPUS_FRAME_SIZE) {
+            match encoder.encode(frame) {
+                Ok(packet) => opus_packets.push(packet),
+                Err(err) => ffi::log_error(&format!("opus encode error: {}", err)),
+            }
+        }
+        pending.drain(..frames * coder::OPUS_FRAME_SIZE);
+
+        Output {
+            audio_data: Vec::new(),
+            opus_packets,
+        }
+    }
+}
+
+/// Per-speaker transcoders for every format a stream of packets is converted to.
+#[derive(Default)]
+pub struct Transcoders {
+    transcoders: HashMap<(i32, Format), Transcoder>,
+    /// When the last packet of each speaker who is still talking arrived.
+    heard: HashMap<i32, Instant>,
+}
+
+impl Transcoders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drops the state of `client_index`, used when a new client takes the slot.
+    pub fn reset(&mut self, client_index: i32) {
+        self.transcoders
+            .retain(|(slot, _), _| *slot != client_index);
+        self.heard.remove(&client_index);
+    }
+
+    /// Notes that a packet from `client_index` arrived at `now`.
+    pub fn heard(&mut self, client_index: i32, now: Instant) {
+        self.heard.insert(client_index, now);
+    }
+
+    /// Finishes the transcoders of speakers whose talk spurt was over by `now`, returning
+    /// the audio they still held back, such as a partial Opus frame.
+    ///
+    /// The next spurt of such a speaker starts from fresh transcoders.
+    pub fn finish_quiet(&mut self, now: Instant) -> Vec<(i32, Format, Output)> {
+        let mut quiet = HashSet::new();
+        self.heard.retain(|client_index, heard| {
+            if now.saturating_duration_since(*heard) < SPURT_GAP {
+                return true;
+            }
+            quiet.insert(*client_index);
+            false
+        });
+
+        let keys: Vec<(i32, Format)> = self
+            .transcoders
+            .keys()
+            .filter(|(client_index, _)| quiet.contains(client_index))
+            .copied()
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| {
+                let output = self.transcoders.remove(&key)?.finish();
+                Some((key.0, key.1, output))
+            })
+            .collect()
+    }
+
+    /// Converts a packet from `client_index`, given as decoded `pcm` and its `celt` frames.
+    pub fn output(
+        &mut self,
+        client_index: i32,
+        format: Format,
+        pcm: &[i16],
+        celt: &[u8],
+    ) -> Output {
+        match format {
+            Format::Celt => Output {
+                audio_data: celt.to_vec(),
+                opus_packets: Vec::new(),
+            },
+            _ => self
+                .transcoders
+                .entry((client_index, format))
+                .or_insert_with(|| Transcoder::new(format))
+                .process(pcm),
+        }
+    }
+
+    /// Forgets formats that no subscriber uses any more.
+    pub fn retain(&mut self, formats: &HashSet<Format>) {
+        self.transcoders
+            .retain(|(_, format), _| formats.contains(format));
+    }
+}
+
+/// A `RecvVoiceData` stream.
+pub struct Subscriber {
+    sender: mpsc::Sender<Result<RecvVoiceResponse, Status>>,
+    /// Ends the stream with an error, taken once the subscriber is disconnected.
+    disconnect: Option<oneshot::Sender<Status>>,
+    filter: Filter,
+    format: Format,
+    /// Conversion state of the packets this subscriber received from each speaker.
+    transcoders: Transcoders,
+    continuous: bool,
+    segmenter: Option<Segmenter>,
+    depth: usize,
+    max_lag: Option<Duration>,
+    /// Messages dropped since the last lag notice went out.
+    dropped: u64,
+    total_dropped: u64,
+    /// When the subscriber fell behind, until it has caught up again.
+    lagging_since: Option<Instant>,
+    /// Messages delivered in a row since the last drop.
+    delivered: usize,
+}
+
+impl Subscriber {
+    /// Subscriber for `request`, with the receiving end of its stream.
+    pub fn new(request: &RecvVoiceRequest) -> Result<(Self, RecvVoiceReceiver)> {
+        let format = Format::new(request)?;
+        let segmenter = Segmenter::new(request)?;
+        let depth = match request.buffer_depth as usize {
+            0 => DEFAULT_BUFFER_DEPTH,
+            depth @ 1..=MAX_BUFFER_DEPTH => depth,
+            depth => {
+                return Err(Status::invalid_argument(format!(
+                    "buffer depth {} is outside 1..={}",
+                    depth, MAX_BUFFER_DEPTH
+                ))
+                .into())
+            }
+        };
+        let max_lag = match request.max_lag_ms {
+            0 => None,
+            millis => Some(Duration::from_millis(millis as u64)),
+        };
+
+        let (sender, messages) = mpsc::channel(depth);
+        let (disconnect, disconnected) = oneshot::channel();
+        let subscriber = Self {
+            sender,
+            disconnect: Some(disconnect),
+            filter: Filter::new(request),
+            format,
+            transcoders: Transcoders::new(),
+            continuous: request.continuous,
+            segmenter,
+            depth,
+            max_lag,
+            dropped: 0,
+            total_dropped: 0,
+            lagging_since: None,
+            delivered: 0,
+        };
+        let receiver = RecvVoiceReceiver {
+            messages,
+            disconnect: Some(disconnected),
+        };
+
+        Ok((subscriber, receiver))
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Converts a packet of `client_index` that arrived at `now` to the subscriber's format,
+    /// given as decoded `pcm` and its `celt` frames.
+    ///
+    /// Every subscriber encodes only the packets it receives, so its stream decodes
+    /// cleanly across the packets its filter leaves out.
+    pub fn output(&mut self, client_index: i32, pcm: &[i16], celt: &[u8], now: Instant) -> Output {
+        self.transcoders.heard(client_index, now);
+        self.transcoders
+            .output(client_index, self.format, pcm, celt)
+    }
+
+    /// Drops the conversion state of `client_index`, used when a new client takes the slot.
+    pub fn reset(&mut self, client_index: i32) {
+        self.transcoders.reset(client_index);
+    }
+
+    /// Finishes the conversion of speakers whose talk spurt was over by `now`, returning
+    /// the audio held back for each, such as a partial Opus frame.
+    pub fn finish_quiet(&mut self, now: Instant) -> Vec<(i32, Output)> {
+        self.transcoders
+            .finish_quiet(now)
+            .into_iter()
+            .map(|(client_index, _, output)| (client_index, output))
+            .collect()
+    }
+
+    /// Whether the subscriber receives speaker timelines rather than packets as they arrive.
+    pub fn is_continuous(&self) -> bool {
+        self.continuous
+    }
+
+    /// Whether the subscriber receives packets as they arrive.
+    pub fn wants_packets(&self) -> bool {
+        !self.continuous
+            && !self
+                .segmenter
+                .as_ref()
+                .map(Segmenter::collects)
+                .unwrap_or(false)
+    }
+
+    /// Feeds a packet to the subscriber's segmenter, sending the events it causes.
+    pub fn segment(
+        &mut self,
+        speaker: &Speaker,
+        userid: i32,
+        pcm: &[i16],
+        celt: &[u8],
+        now: Instant,
+        tick: i32,
+    ) {
+        if let Some(segmenter) = &mut self.segmenter {
+            let events = segmenter.push(speaker, userid, pcm, celt, now);
+            self.send_events(events, tick);
+        }
+    }
+
+    /// Ends the utterances that went quiet by `now`.
+    pub fn tick(&mut self, now: Instant, tick: i32) {
+        if let Some(segmenter) = &mut self.segmenter {
+            let events = segmenter.tick(now);
+            self.send_events(events, tick);
+        }
+    }
+
+    fn send_events(&mut self, events: Vec<Event>, tick: i32) {
+        let collect = match &self.segmenter {
+            Some(segmenter) => segmenter.collects(),
+            None => return,
+        };
+
+        for event in events {
+            let (speaker, talk_event, output) = match event {
+                Event::Start { .. } if collect && !self.talk_events() => continue,
+                Event::Start {
+                    speaker,
+                    utterance,
+                    start,
+                } => (
+                    speaker,
+                    TalkEvent {
+                        kind: TalkEventKind::Start as i32,
+                        utterance,
+                        start_us: unix_micros(start),
+                        duration_ms: 0,
+                    },
+                    Output::default(),
+                ),
+                Event::End {
+                    speaker,
+                    utterance,
+                    start,
+                    duration,
+                    pcm,
+                    celt,
+                } => {
+                    let output = match self.format {
+                        _ if !collect => Output::default(),
+                        Format::Celt => Output {
+                            audio_data: celt,
+                            opus_packets: Vec::new(),
+                        },
+                        format => {
+                            let mut transcoder = Transcoder::new(format);
+                            let mut output = transcoder.process(&pcm);
+                            let tail = transcoder.finish();
+                            output.audio_data.extend(tail.audio_data);
+                            output.opus_packets.extend(tail.opus_packets);
+                            output
+                        }
+                    };
+
+                    (
+                        speaker,
+                        TalkEvent {
+                            kind: TalkEventKind::End as i32,
+                            utterance,
+                            start_us: unix_micros(start),
+                            duration_ms: duration.as_millis() as u32,
+                        },
+                        output,
+                    )
+                }
+            };
+
+            self.send(RecvVoiceResponse {
+                steamid: speaker.steamid,
+                audio_data: output.audio_data,
+                speaker: Some(speaker),
+                sequence: talk_event.utterance,
+                timestamp_us: talk_event.start_us,
+                tick,
+                encoding: self.format.encoding() as i32,
+                sample_rate: self.format.sample_rate(),
+                opus_packets: output.opus_packets,
+                talk_event: Some(talk_event),
+                ..RecvVoiceResponse::default()
+            });
+        }
+    }
+
+    fn talk_events(&self) -> bool {
+        self.segmenter
+            .as_ref()
+            .map(Segmenter::reports)
+            .unwrap_or(false)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.disconnect.is_none() || self.sender.is_closed()
+    }
+
+    pub fn wants(&self, speaker: &Speaker, level: u32) -> bool {
+        self.filter.matches(speaker, level)
+    }
+
+    /// Whether the subscriber listens to `speaker` at all, for audio that is not tied
+    /// to a single packet's level.
+    pub fn wants_speaker(&self, speaker: &Speaker) -> bool {
+        self.filter.matches_speaker(speaker)
+    }
+
+    /// Queues `response`, or counts it as dropped if the subscriber has fallen behind.
+    ///
+    /// The next message that fits after a drop is preceded by a lag notice. A subscriber
+    /// counts as caught up once a whole buffer of messages went through without a drop,
+    /// and is disconnected if it stays behind for longer than its `max_lag`.
+    pub fn send(&mut self, response: RecvVoiceResponse) {
+        if self.disconnect.is_none() {
+            return;
+        }
+
+        if self.dropped > 0 {
+            let notice = RecvVoiceResponse {
+                lagged: Some(Lagged {
+                    dropped: self.dropped,
+                    total_dropped: self.total_dropped,
+                }),
+                ..RecvVoiceResponse::default()
+            };
+            if self.sender.try_send(Ok(notice)).is_ok() {
+                self.dropped = 0;
+            }
+        }
+
+        let sent = self.dropped == 0 && self.sender.try_send(Ok(response)).is_ok();
+        if sent {
+            self.delivered += 1;
+            if self.delivered >= self.depth {
+                self.lagging_since = None;
+            }
+            return;
+        }
+
+        self.dropped += 1;
+        self.total_dropped += 1;
+        self.delivered = 0;
+        let lagging_since = *self.lagging_since.get_or_insert_with(Instant::now);
+
+        if let Some(max_lag) = self.max_lag {
+            if lagging_since.elapsed() > max_lag {
+                self.disconnect(max_lag);
+            }
+        }
+    }
+
+    /// Ends the stream with an error once the reader has taken what was queued.
+    fn disconnect(&mut self, max_lag: Duration) {
+        let status = Status::resource_exhausted(format!(
+            "fell behind for more than {} ms, {} messages dropped",
+            max_lag.as_millis(),
+            self.total_dropped
+        ));
+        if let Some(disconnect) = self.disconnect.take() {
+            let _ = disconnect.send(status);
+        }
+    }
+}
+
+pub fn speaker(client: &ffi::ClientInfo) -> Speaker {
+    Speaker {
+        client_index: client.client_index,
+        steamid: client.steamid,
+        name: String::from_utf8_lossy(&client.name).into_owned(),
+        team: client.team,
+        alive: client.alive,
+        bot: client.fake_client,
+    }
+}
+
+/// `time` in microseconds since the Unix epoch.
+pub fn unix_micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+/// Root mean square of `pcm`, on the same 0..32767 scale as the samples.
+pub fn level(pcm: &[i16]) -> u32 {
+    if pcm.is_empty() {
+        return 0;
+    }
+
+    let sum: f64 = pcm.iter().map(|sample| (*sample as f64).powi(2)).sum();
+    (sum / pcm.len() as f64).sqrt() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    fn player(client_index: i32, steamid: u64, team: i32) -> Speaker {
+        Speaker {
+            client_index,
+            steamid,
+            team,
+            ..Speaker::default()
+        }
+    }
+
+    #[test]
+    fn transcoder_holds_partial_opus_frames_until_finished() {
+        let mut transcoder = Transcoder::new(Format::Opus);
+        let mut resampler = Resampler::new(coder::SAMPLE_RATE, coder::OPUS_SAMPLE_RATE);
+        let frame = vec![1000; coder::FRAME_SIZE];
+
+        let mut samples = 0;
+        let mut packets = 0;
+        for _ in 0..10 {
+            samples += resampler.process(&frame).len();
+            packets += transcoder.process(&frame).opus_packets.len();
+            assert_eq!(packets, samples / coder::OPUS_FRAME_SIZE);
+        }
+
+        samples += resampler.flush().len();
+        // The last frame is padded with silence rather than held back.
+        packets += transcoder.finish().opus_packets.len();
+        assert!(packets * coder::OPUS_FRAME_SIZE >= samples);
+        assert!((packets - 1) * coder::OPUS_FRAME_SIZE < samples);
+    }
+
+    #[test]
+    fn transcoders_finish_speakers_who_went_quiet() {
+        let mut transcoders = Transcoders::new();
+        let start = Instant::now();
+        let frame = vec![1000; coder::FRAME_SIZE];
+        transcoders.heard(1, start);
+        transcoders.output(1, Format::Opus, &frame, &[]);
+        transcoders.heard(2, start + SPURT_GAP / 2);
+        transcoders.output(2, Format::Opus, &frame, &[]);
+
+        let tails = transcoders.finish_quiet(start + SPURT_GAP);
+        assert_eq!(tails.len(), 1);
+        assert_eq!((tails[0].0, tails[0].1), (1, Format::Opus));
+        assert_eq!(tails[0].2.opus_packets.len(), 1);
+
+        assert!(transcoders.finish_quiet(start + SPURT_GAP).is_empty());
+        assert_eq!(transcoders.finish_quiet(start + SPURT_GAP * 2).len(), 1);
+    }
+
+    #[test]
+    fn empty_filter_matches_everyone() {
+        let filter = Filter::new(&RecvVoiceRequest::default());
+        let bot = Speaker {
+            bot: true,
+            ..player(3, 0, 2)
+        };
+
+        assert!(filter.matches(&player(1, 76561198000000001, 2), 0));
+        assert!(filter.matches(&bot, 0));
+    }
+
+    #[test]
+    fn filter_selects_speakers() {
+        let filter = Filter::new(&RecvVoiceRequest {
+            steamids: vec![1, 2],
+            exclude_steamids: vec![2],
+            client_indexes: vec![4, 5],
+            team: 3,
+            ..RecvVoiceRequest::default()
+        });
+
+        assert!(filter.matches(&player(4, 1, 3), 0));
+        assert!(!filter.matches(&player(4, 2, 3), 0));
+        assert!(!filter.matches(&player(4, 3, 3), 0));
+        assert!(!filter.matches(&player(6, 1, 3), 0));
+        assert!(!filter.matches(&player(4, 1, 2), 0));
+    }
+
+    #[test]
+    fn filter_can_exclude_bots() {
+        let filter = Filter::new(&RecvVoiceRequest {
+            exclude_bots: true,
+            ..RecvVoiceRequest::default()
+        });
+        let bot = Speaker {
+            bot: true,
+            ..player(3, 0, 2)
+        };
+
+        assert!(!filter.matches(&bot, 0));
+        assert!(filter.matches(&player(1, 1, 2), 0));
+    }
+
+    #[test]
+    fn level_threshold_is_ignored_by_continuous_subscriptions() {
+        let request = RecvVoiceRequest {
+            min_level: 100,
+            ..RecvVoiceRequest::default()
+        };
+        let filter = Filter::new(&request);
+        assert!(!filter.matches(&player(1, 1, 2), 99));
+        assert!(filter.matches(&player(1, 1, 2), 100));
+
+        let filter = Filter::new(&RecvVoiceRequest {
+            continuous: true,
+            ..request
+        });
+        assert!(filter.matches(&player(1, 1, 2), 0));
+    }
+
+    fn subscriber(buffer_depth: u32, max_lag_ms: u32) -> (Subscriber, RecvVoiceReceiver) {
+        Subscriber::new(&RecvVoiceRequest {
+            buffer_depth,
+            max_lag_ms,
+            ..RecvVoiceRequest::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn subscribers_encode_only_the_packets_they_receive() {
+        let request = RecvVoiceRequest {
+            encoding: AudioEncoding::Opus as i32,
+            ..RecvVoiceRequest::default()
+        };
+        let (mut everything, _) = Subscriber::new(&request).unwrap();
+        let (mut filtered, _) = Subscriber::new(&request).unwrap();
+        let frame = vec![1000; coder::FRAME_SIZE];
+        let start = Instant::now();
+
+        // The filtered subscriber skips the first two packets of speaker 1.
+        let mut packets = 0;
+        for _ in 0..3 {
+            packets += everything.output(1, &frame, &[], start).opus_packets.len();
+        }
+        let skipped = filtered.output(1, &frame, &[], start).opus_packets.len();
+
+        let mut fresh = Transcoder::new(Format::Opus);
+        assert_eq!(skipped, fresh.process(&frame).opus_packets.len());
+        let mut whole = Transcoder::new(Format::Opus);
+        let expected: usize = (0..3)
+            .map(|_| whole.process(&frame).opus_packets.len())
+            .sum();
+        assert_eq!(packets, expected);
+
+        // Each tail holds back what that subscriber's own stream left over.
+        let tail = |subscriber: &mut Subscriber| {
+            subscriber
+                .finish_quiet(start + SPURT_GAP)
+                .into_iter()
+                .map(|(client_index, output)| (client_index, output.opus_packets.len()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tail(&mut everything),
+            vec![(1, whole.finish().opus_packets.len())]
+        );
+        assert_eq!(
+            tail(&mut filtered),
+            vec![(1, fresh.finish().opus_packets.len())]
+        );
+    }
+
+    fn message(tick: i32) -> RecvVoiceResponse {
+        RecvVoiceResponse {
+            tick,
+            ..RecvVoiceResponse::default()
+        }
+    }
+
+    /// Takes what the stream yields without waiting, as ticks or lag notices.
+    fn received(receiver: &mut RecvVoiceReceiver) -> Vec<std::result::Result<String, Status>> {
+        let mut received = Vec::new();
+        while let Some(Some(message)) = receiver.next().now_or_never() {
+            received.push(message.map(|message| match message.lagged {
+                Some(lagged) => format!("lagged {}/{}", lagged.dropped, lagged.total_dropped),
+                None => format!("tick {}", message.tick),
+            }));
+        }
+        received
+    }
+
+    #[test]
+    fn send_precedes_the_next_message_after_a_drop_with_a_lag_notice() {
+        let (mut subscriber, mut receiver) = subscriber(2, 0);
+        for tick in 0..4 {
+            subscriber.send(message(tick));
+        }
+        assert_eq!(
+            received(&mut receiver)
+                .into_iter()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>(),
+            vec!["tick 0", "tick 1"]
+        );
+
+        subscriber.send(message(4));
+        subscriber.send(message(5));
+        subscriber.send(message(6));
+        assert_eq!(
+            received(&mut receiver)
+                .into_iter()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>(),
+            vec!["lagged 2/2", "tick 4"]
+        );
+        assert_eq!(subscriber.dropped, 2);
+        assert_eq!(subscriber.total_dropped, 4);
+    }
+
+    #[test]
+    fn send_counts_as_caught_up_after_a_whole_buffer_goes_through() {
+        let (mut subscriber, mut receiver) = subscriber(2, 0);
+        for tick in 0..3 {
+            subscriber.send(message(tick));
+        }
+        received(&mut receiver);
+        assert!(subscriber.lagging_since.is_some());
+
+        // The lag notice takes the first slot, so one message is not yet enough.
+        subscriber.send(message(3));
+        received(&mut receiver);
+        assert!(subscriber.lagging_since.is_some());
+
+        subscriber.send(message(4));
+        assert!(subscriber.lagging_since.is_none());
+        assert!(!subscriber.is_closed());
+    }
+
+    #[test]
+    fn send_disconnects_subscribers_behind_for_longer_than_max_lag() {
+        let (mut subscriber, mut receiver) = subscriber(1, 1);
+        subscriber.send(message(0));
+        subscriber.send(message(1));
+        std::thread::sleep(Duration::from_millis(5));
+        subscriber.send(message(2));
+        assert!(subscriber.is_closed());
+
+        // The queued message still arrives, then the stream ends with the reason.
+        let received = received(&mut receiver);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].as_ref().unwrap(), "tick 0");
+        assert_eq!(
+            received[1].as_ref().unwrap_err().code(),
+            tonic::Code::ResourceExhausted
+        );
+        // The stream is over even while the subscriber is still around.
+        assert_eq!(
+            receiver.next().now_or_never().map(|next| next.is_none()),
+            Some(true)
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::f64::consts::PI;
+
+/// Kernel taps on each side of the interpolated position.
+const HALF_TAPS: usize = 16;
+/// Upper bound on precomputed kernel phases for awkward rate ratios.
+const MAX_PHASES: usize = 1024;
+
+/// Range of sample rates accepted from and delivered to clients.
+pub const MIN_SAMPLE_RATE: u32 = 8000;
+pub const MAX_SAMPLE_RATE: u32 = 192000;
+
+/// Windowed-sinc kernel with cutoff `cutoff` (relative to the input Nyquist frequency).
+fn kernel(x: f64, cutoff: f64) -> f64 {
+    if x.abs() >= HALF_TAPS as f64 {
+        return 0.0;
+    }
+
+    let sinc = if x == 0.0 {
+        1.0
+    } else {
+        (PI * cutoff * x).sin() / (PI * cutoff * x)
+    };
+    let n = x / HALF_TAPS as f64;
+    let window = 0.42 + 0.5 * (PI * n).cos() + 0.08 * (2.0 * PI * n).cos();
+
+    cutoff * sinc * window
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Streaming band-limited sample rate converter for mono s16 audio.
+///
+/// Output positions advance by an exact rational step, so long streams do not drift, and
+/// the kernel cutoff follows the lower of the two rates to avoid aliasing when
+/// downsampling.
+pub struct Resampler {
+    from: u32,
+    to: u32,
+    phases: usize,
+    table: Vec<f32>,
+    buffer: Vec<f32>,
+    index: usize,
+    frac: u32,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32) -> Self {
+        let divisor = gcd(from, to).max(1);
+        let (from, to) = (from / divisor, to / divisor);
+
+        let cutoff = 0.95 * (to as f64 / from as f64).min(1.0);
+        let phases = (to as usize).min(MAX_PHASES);
+        let mut table = Vec::with_capacity(phases * 2 * HALF_TAPS);
+        for phase in 0..phases {
+            let frac = phase as f64 / phases as f64;
+            let taps: Vec<f64> = (0..2 * HALF_TAPS)
+                .map(|tap| kernel(tap as f64 - (HALF_TAPS - 1) as f64 - frac, cutoff))
+                .collect();
+            // Normalise every phase to unity gain so DC passes through unchanged.
+            let sum: f64 = taps.iter().sum();
+            table.extend(taps.iter().map(|tap| (tap / sum) as f32));
+        }
+
+        Self {
+            from,
+            to,
+            phases,
+            table,
+            buffer: vec![0.0; HALF_TAPS - 1],
+            index: HALF_TAPS - 1,
+            frac: 0,
+        }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.from == self.to
+    }
+
+    /// Converts the next chunk of input, returning whatever output it completes.
+    pub fn process(&mut self, input: &[i16]) -> Vec<i16> {
+        if self.is_passthrough() {
+            return input.to_vec();
+        }
+
+        self.buffer
+            .extend(input.iter().map(|sample| *sample as f32));
+
+        let mut output = Vec::new();
+        while self.index + HALF_TAPS < self.buffer.len() {
+            let phase = self.frac as usize * self.phases / self.to as usize;
+            let taps = &self.table[phase * 2 * HALF_TAPS..(phase + 1) * 2 * HALF_TAPS];
+            let start = self.index + 1 - HALF_TAPS;
+            let sum: f32 = self.buffer[start..start + 2 * HALF_TAPS]
+                .iter()
+                .zip(taps.iter())
+                .map(|(sample, tap)| sample * tap)
+                .sum();
+            output.push(sum.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+
+            self.frac += self.from;
+            self.index += (self.frac / self.to) as usize;
+            self.frac %= self.to;
+        }
+
+        let consumed = self.index + 1 - HALF_TAPS;
+        self.buffer.drain(..consumed);
+        self.index -= consumed;
+
+        output
+    }
+
+    /// Returns the output still held back waiting for lookahead, as if the input ended.
+    pub fn flush(&mut self) -> Vec<i16> {
+        if self.is_passthrough() {
+            return Vec::new();
+        }
+
+        self.process(&[0; HALF_TAPS])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(rate: u32, frequency: f64, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|i| (10000.0 * (2.0 * PI * frequency * i as f64 / rate as f64).sin()) as i16)
+            .collect()
+    }
+
+    fn rms(samples: &[i16]) -> f64 {
+        let sum: f64 = samples.iter().map(|s| *s as f64 * *s as f64).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn equal_rates_pass_through() {
+        let mut resampler = Resampler::new(22050, 22050);
+        assert!(resampler.is_passthrough());
+        assert_eq!(resampler.process(&[1, 2, 3]), vec![1, 2, 3]);
+        assert!(resampler.flush().is_empty());
+    }
+
+    #[test]
+    fn output_length_follows_the_rate_ratio_across_chunks() {
+        let mut resampler = Resampler::new(48000, 22050);
+        let mut output = 0;
+        for chunk in sine(48000, 440.0, 48000).chunks(777) {
+            output += resampler.process(chunk).len();
+        }
+        output += resampler.flush().len();
+
+        assert!((output as i64 - 22050).abs() <= HALF_TAPS as i64);
+    }
+
+    #[test]
+    fn dc_passes_unchanged() {
+        let mut resampler = Resampler::new(44100, 22050);
+        let output = resampler.process(&[5000; 4410]);
+
+        assert!(output[100..]
+            .iter()
+            .all(|sample| (*sample - 5000).abs() <= 1));
+    }
+
+    #[test]
+    fn downsampling_keeps_the_passband_and_rejects_aliases() {
+        let mut resampler = Resampler::new(48000, 22050);
+        let passband = resampler.process(&sine(48000, 1000.0, 48000));
+        assert!((rms(&passband[100..]) - 10000.0 / 2f64.sqrt()).abs() < 200.0);
+
+        // 15 kHz is above the output Nyquist frequency and would fold back to 7050 Hz.
+        let mut resampler = Resampler::new(48000, 22050);
+        let alias = resampler.process(&sine(48000, 15000.0, 48000));
+        assert!(rms(&alias[100..]) < 100.0);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::coder;
+use crate::ffi;
+use crate::recv::{self, Format, Output, Transcoders};
+use crate::voiceserver::{FrameKind, Speaker};
+
+/// Frames a talk spurt buffers before it starts playing, to absorb network jitter.
+const JITTER_FRAMES: usize = 3;
+/// Longest a talk spurt waits for `JITTER_FRAMES` before it starts playing anyway.
+const JITTER_DELAY: Duration = Duration::from_millis(70);
+/// Buffered frames beyond which the oldest are dropped, for clients whose clock runs fast.
+const MAX_BUFFERED_FRAMES: usize = 12;
+/// Missing frames concealed before the speaker counts as silent.
+const MAX_CONCEALED_FRAMES: u32 = 4;
+
+/// Time from the start of a timeline to the start of frame `frames`.
+pub fn frames_duration(frames: u64) -> Duration {
+    Duration::from_nanos(
+        (frames as u128 * coder::FRAME_SIZE as u128 * 1_000_000_000 / coder::SAMPLE_RATE as u128)
+            as u64,
+    )
+}
+
+/// Number of frames that started within `elapsed` of the start of a timeline.
+pub fn frames_started(elapsed: Duration) -> u64 {
+    (elapsed.as_nanos() * coder::SAMPLE_RATE as u128 / (coder::FRAME_SIZE as u128 * 1_000_000_000))
+        as u64
+        + 1
+}
+
+/// One frame of a speaker's timeline.
+#[derive(Clone)]
+pub struct Frame {
+    pub speaker: Speaker,
+    /// Index of the frame since the timeline started.
+    pub sequence: u64,
+    /// Wall-clock time the frame starts at.
+    pub timestamp: SystemTime,
+    pub kind: FrameKind,
+    pub pcm: Vec<i16>,
+    /// Volume the server plays the speaker at, from the plugin-set volume map.
+    pub volume: f32,
+}
+
+/// A speaker's voice on the server clock, one CELT frame at a time.
+struct Timeline {
+    userid: i32,
+    speaker: Speaker,
+    volume: f32,
+    decoder: coder::Decoder,
+    start: Instant,
+    start_time: SystemTime,
+    emitted: u64,
+    buffer: VecDeque<Vec<u8>>,
+    /// Whether a talk spurt is playing out of the buffer.
+    playing: bool,
+    /// When the buffer started filling for the next talk spurt.
+    waiting_since: Option<Instant>,
+    concealed: u32,
+}
+
+impl Timeline {
+    fn new(client: &ffi::ClientInfo, now: Instant) -> Self {
+        Self {
+            userid: client.userid,
+            speaker: recv::speaker(client),
+            volume: 1.0,
+            decoder: coder::Decoder::new(),
+            start: now,
+            start_time: SystemTime::now(),
+            emitted: 0,
+            buffer: VecDeque::new(),
+            playing: false,
+            waiting_since: None,
+            concealed: 0,
+        }
+    }
+
+    fn push(&mut self, celt: &[u8], now: Instant) {
+        self.buffer.extend(
+            celt.chunks_exact(coder::PACKET_SIZE)
+                .map(|packet| packet.to_vec()),
+        );
+        // The client's clock runs ahead of the server's; catch up by dropping audio.
+        while self.buffer.len() > MAX_BUFFERED_FRAMES {
+            self.buffer.pop_front();
+        }
+
+        if !self.playing && self.waiting_since.is_none() {
+            self.waiting_since = Some(now);
+        }
+    }
+
+    fn next(&mut self, now: Instant) -> (FrameKind, Vec<i16>) {
+        let mut pcm = vec![0; coder::FRAME_SIZE];
+
+        if !self.playing {
+            let waited = self
+                .waiting_since
+                .map(|since| now.saturating_duration_since(since) >= JITTER_DELAY)
+                .unwrap_or(false);
+            if self.buffer.len() >= JITTER_FRAMES || (waited && !self.buffer.is_empty()) {
+                self.playing = true;
+                self.waiting_since = None;
+                self.concealed = 0;
+            }
+        }
+        if !self.playing {
+            return (FrameKind::Silence, pcm);
+        }
+
+        if let Some(packet) = self.buffer.pop_front() {
+            match self.decoder.decode(&packet, &mut pcm) {
+                Ok(_) => {
+                    self.concealed = 0;
+                    return (FrameKind::Voice, pcm);
+                }
+                Err(err) => ffi::log_error(&format!("decode error: {}", err)),
+            }
+        }
+
+        if self.concealed < MAX_CONCEALED_FRAMES {
+            self.concealed += 1;
+            match self.decoder.conceal(&mut pcm) {
+                Ok(_) => return (FrameKind::Concealed, pcm),
+                Err(err) => ffi::log_error(&format!("conceal error: {}", err)),
+            }
+        }
+
+        // The talk spurt is over; the next one buffers again before it plays.
+        self.playing = self.concealed < MAX_CONCEALED_FRAMES;
+        (FrameKind::Silence, vec![0; coder::FRAME_SIZE])
+    }
+}
+
+/// Timelines of every speaker heard since a continuous subscription started.
+///
+/// Each speaker's timeline starts with their first packet and runs on the server clock
+/// until they leave, so its frames are gap-free: lost or late frames are concealed with
+/// the CELT decoder, and silence fills the time between talk spurts.
+#[derive(Default)]
+pub struct Timelines {
+    timelines: HashMap<i32, Timeline>,
+    transcoders: Transcoders,
+}
+
+impl Timelines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffers a voice packet of `client`, played at `volume`, starting their timeline if
+    /// needed.
+    pub fn push(&mut self, client: &ffi::ClientInfo, volume: f32, celt: &[u8], now: Instant) {
+        let timeline = self
+            .timelines
+            .entry(client.client_index)
+            .or_insert_with(|| Timeline::new(client, now));
+        if timeline.userid != client.userid {
+            *timeline = Timeline::new(client, now);
+            self.transcoders.reset(client.client_index);
+        }
+        timeline.speaker = recv::speaker(client);
+        timeline.volume = volume;
+        timeline.push(celt, now);
+    }
+
+    /// Returns the frames that became due by `now`, in order per speaker.
+    ///
+    /// Timelines of speakers who left, as reported by `client_info`, end here.
+    pub fn tick<F>(&mut self, now: Instant, mut client_info: F) -> Vec<Frame>
+    where
+        F: FnMut(i32) -> ffi::ClientInfo,
+    {
+        let transcoders = &mut self.transcoders;
+        self.timelines.retain(|client_index, timeline| {
+            let client = client_info(*client_index);
+            if !client.in_game || client.userid != timeline.userid {
+                transcoders.reset(*client_index);
+                return false;
+            }
+            timeline.speaker = recv::speaker(&client);
+            true
+        });
+
+        let mut frames = Vec::new();
+        for timeline in self.timelines.values_mut() {
+            let due = frames_started(now.saturating_duration_since(timeline.start));
+            while timeline.emitted < due {
+                let (kind, pcm) = timeline.next(now);
+                frames.push(Frame {
+                    speaker: timeline.speaker.clone(),
+                    sequence: timeline.emitted,
+                    timestamp: timeline.start_time + frames_duration(timeline.emitted),
+                    kind,
+                    pcm,
+                    volume: timeline.volume,
+                });
+                timeline.emitted += 1;
+            }
+        }
+
+        frames
+    }
+
+    /// Converts a frame to a subscription format, keeping state per speaker and format.
+    pub fn output(&mut self, frame: &Frame, format: Format) -> Output {
+        self.transcoders
+            .output(frame.speaker.client_index, format, &frame.pcm, &[])
+    }
+
+    /// Forgets formats that no continuous subscriber uses any more.
+    pub fn retain(&mut self, formats: &HashSet<Format>) {
+        self.transcoders.retain(formats);
+    }
+
+    /// Ends every timeline, once no continuous subscriber is left.
+    pub fn clear(&mut self) {
+        self.timelines.clear();
+        self.transcoders = Transcoders::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(client_index: i32, userid: i32) -> ffi::ClientInfo {
+        ffi::ClientInfo {
+            client_index,
+            in_game: true,
+            fake_client: false,
+            userid,
+            steamid: 0,
+            name: Vec::new(),
+            team: 0,
+            alive: true,
+        }
+    }
+
+    fn packets(count: usize) -> Vec<u8> {
+        vec![0; count * coder::PACKET_SIZE]
+    }
+
+    fn kinds(timeline: &mut Timeline, now: Instant, count: usize) -> Vec<FrameKind> {
+        (0..count).map(|_| timeline.next(now).0).collect()
+    }
+
+    #[test]
+    fn talk_spurts_buffer_jitter_frames_before_playing() {
+        let now = Instant::now();
+        let mut timeline = Timeline::new(&client(0, 2), now);
+
+        timeline.push(&packets(JITTER_FRAMES - 1), now);
+        assert_eq!(timeline.next(now).0, FrameKind::Silence);
+
+        timeline.push(&packets(1), now);
+        assert_eq!(
+            kinds(&mut timeline, now, JITTER_FRAMES),
+            vec![FrameKind::Voice; JITTER_FRAMES]
+        );
+    }
+
+    #[test]
+    fn talk_spurts_start_after_the_jitter_delay_with_fewer_frames() {
+        let now = Instant::now();
+        let mut timeline = Timeline::new(&client(0, 2), now);
+
+        timeline.push(&packets(1), now);
+        assert_eq!(
+            timeline
+                .next(now + JITTER_DELAY - Duration::from_millis(1))
+                .0,
+            FrameKind::Silence
+        );
+        assert_eq!(timeline.next(now + JITTER_DELAY).0, FrameKind::Voice);
+    }
+
+    #[test]
+    fn short_gaps_are_concealed_without_buffering_again() {
+        let now = Instant::now();
+        let mut timeline = Timeline::new(&client(0, 2), now);
+
+        timeline.push(&packets(JITTER_FRAMES), now);
+        kinds(&mut timeline, now, JITTER_FRAMES);
+        assert_eq!(kinds(&mut timeline, now, 2), vec![FrameKind::Concealed; 2]);
+
+        timeline.push(&packets(1), now);
+        assert_eq!(timeline.next(now).0, FrameKind::Voice);
+    }
+
+    #[test]
+    fn long_gaps_end_the_talk_spurt() {
+        let now = Instant::now();
+        let mut timeline = Timeline::new(&client(0, 2), now);
+
+        timeline.push(&packets(JITTER_FRAMES), now);
+        kinds(&mut timeline, now, JITTER_FRAMES);
+        assert_eq!(
+            kinds(&mut timeline, now, MAX_CONCEALED_FRAMES as usize + 2),
+            vec![
+                FrameKind::Concealed,
+                FrameKind::Concealed,
+                FrameKind::Concealed,
+                FrameKind::Concealed,
+                FrameKind::Silence,
+                FrameKind::Silence,
+            ]
+        );
+
+        // The next talk spurt buffers again.
+        timeline.push(&packets(1), now);
+        assert_eq!(timeline.next(now).0, FrameKind::Silence);
+    }
+
+    #[test]
+    fn fast_clients_lose_their_oldest_frames() {
+        let now = Instant::now();
+        let mut timeline = Timeline::new(&client(0, 2), now);
+
+        timeline.push(&packets(MAX_BUFFERED_FRAMES + 5), now);
+        assert_eq!(timeline.buffer.len(), MAX_BUFFERED_FRAMES);
+        assert_eq!(
+            kinds(&mut timeline, now, MAX_BUFFERED_FRAMES + 1).last(),
+            Some(&FrameKind::Concealed)
+        );
+    }
+
+    #[test]
+    fn tick_emits_due_frames_until_the_speaker_leaves() {
+        let now = Instant::now();
+        let mut timelines = Timelines::new();
+        timelines.push(&client(3, 7), 0.5, &packets(JITTER_FRAMES), now);
+
+        // 100 ms covers the starts of the first five frames.
+        let frames = timelines.tick(now + Duration::from_millis(100), |index| client(index, 7));
+        let sequences: Vec<u64> = frames.iter().map(|frame| frame.sequence).collect();
+        assert_eq!(sequences, vec![0, 1, 2, 3, 4]);
+        assert!(frames
+            .iter()
+            .all(|frame| frame.speaker.client_index == 3 && frame.volume == 0.5));
+        assert_eq!(frames[2].kind, FrameKind::Voice);
+        assert_eq!(frames[3].kind, FrameKind::Concealed);
+
+        let frames = timelines.tick(now + Duration::from_millis(200), |index| ffi::ClientInfo {
+            in_game: false,
+            ..client(index, 7)
+        });
+        assert!(frames.is_empty());
+        assert!(timelines.timelines.is_empty());
+    }
+}
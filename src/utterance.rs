@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use tonic::Status;
+
+use crate::coder;
+use crate::error::Result;
+use crate::recv;
+use crate::voiceserver::{RecvVoiceRequest, Speaker};
+
+/// Silence that ends an utterance unless the subscription asks for another gap.
+const DEFAULT_GAP: Duration = Duration::from_millis(300);
+/// Longest gap a subscription may ask for.
+const MAX_GAP: Duration = Duration::from_secs(10);
+/// Longest utterance delivered in one message; longer speech is split here.
+const MAX_UTTERANCE: Duration = Duration::from_secs(60);
+
+/// Change in whether a speaker is talking.
+pub enum Event {
+    Start {
+        speaker: Speaker,
+        utterance: u64,
+        start: SystemTime,
+    },
+    End {
+        speaker: Speaker,
+        utterance: u64,
+        start: SystemTime,
+        /// Length of the utterance up to its last speech frame.
+        duration: Duration,
+        /// The utterance's audio, only collected in utterance mode.
+        pcm: Vec<i16>,
+        celt: Vec<u8>,
+    },
+}
+
+struct Talk {
+    speaker: Speaker,
+    utterance: u64,
+    start: SystemTime,
+    last_active: Instant,
+    frames: usize,
+    /// Frames up to and including the last one that counted as speech.
+    active_frames: usize,
+    pcm: Vec<i16>,
+    celt: Vec<u8>,
+}
+
+impl Talk {
+    fn end(mut self) -> Event {
+        self.pcm.truncate(self.active_frames * coder::FRAME_SIZE);
+        self.celt.truncate(self.active_frames * coder::PACKET_SIZE);
+
+        Event::End {
+            speaker: self.speaker,
+            utterance: self.utterance,
+            start: self.start,
+            duration: Duration::from_nanos(
+                (self.active_frames * coder::FRAME_SIZE) as u64 * 1_000_000_000
+                    / coder::SAMPLE_RATE as u64,
+            ),
+            pcm: self.pcm,
+            celt: self.celt,
+        }
+    }
+}
+
+#[derive(Default)]
+struct SpeakerState {
+    userid: i32,
+    next_utterance: u64,
+    talk: Option<Talk>,
+}
+
+/// Splits each speaker's packets into utterances for one subscription.
+///
+/// CELT packets carry no end-of-speech marker, so an utterance ends once the speaker
+/// has not sent anything, or with a VAD level nothing loud enough, for the gap.
+pub struct Segmenter {
+    gap: Duration,
+    vad_level: u32,
+    report: bool,
+    collect: bool,
+    speakers: HashMap<i32, SpeakerState>,
+}
+
+impl Segmenter {
+    /// Segmenter for `request`, `None` if it asks for neither talk events nor utterances.
+    pub fn new(request: &RecvVoiceRequest) -> Result<Option<Self>> {
+        if !request.talk_events && !request.utterances {
+            return Ok(None);
+        }
+        if request.utterances && request.continuous {
+            return Err(Status::invalid_argument(
+                "utterances and continuous subscriptions exclude each other",
+            )
+            .into());
+        }
+
+        let gap = match Duration::from_millis(request.talk_gap_ms as u64) {
+            gap if gap.is_zero() => DEFAULT_GAP,
+            gap if gap > MAX_GAP => {
+                return Err(Status::invalid_argument(format!(
+                    "talk gap must be at most {} ms",
+                    MAX_GAP.as_millis()
+                ))
+                .into())
+            }
+            gap => gap,
+        };
+
+        Ok(Some(Self {
+            gap,
+            vad_level: request.vad_level,
+            report: request.talk_events,
+            collect: request.utterances,
+            speakers: HashMap::new(),
+        }))
+    }
+
+    /// Whether talk start and end events are reported on their own.
+    pub fn reports(&self) -> bool {
+        self.report
+    }
+
+    /// Whether finished utterances are delivered with their audio.
+    pub fn collects(&self) -> bool {
+        self.collect
+    }
+
+    /// Feeds a packet of `speaker`, given as decoded `pcm` and its `celt` frames.
+    pub fn push(
+        &mut self,
+        speaker: &Speaker,
+        userid: i32,
+        pcm: &[i16],
+        celt: &[u8],
+        now: Instant,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        let state = self.speakers.entry(speaker.client_index).or_default();
+        if state.userid != userid {
+            events.extend(state.talk.take().map(Talk::end));
+            *state = SpeakerState {
+                userid,
+                ..SpeakerState::default()
+            };
+        }
+
+        let max_frames =
+            (MAX_UTTERANCE.as_secs() * coder::SAMPLE_RATE as u64) as usize / coder::FRAME_SIZE;
+        for (frame, packet) in pcm
+            .chunks_exact(coder::FRAME_SIZE)
+            .zip(celt.chunks_exact(coder::PACKET_SIZE))
+        {
+            let active = self.vad_level == 0 || recv::level(frame) >= self.vad_level;
+
+            if let Some(talk) = &state.talk {
+                if now.saturating_duration_since(talk.last_active) >= self.gap {
+                    events.extend(state.talk.take().map(Talk::end));
+                }
+            }
+            let talk = match &mut state.talk {
+                Some(talk) => talk,
+                None if active => {
+                    let talk = Talk {
+                        speaker: speaker.clone(),
+                        utterance: state.next_utterance,
+                        start: SystemTime::now(),
+                        last_active: now,
+                        frames: 0,
+                        active_frames: 0,
+                        pcm: Vec::new(),
+                        celt: Vec::new(),
+                    };
+                    state.next_utterance += 1;
+                    events.push(Event::Start {
+                        speaker: talk.speaker.clone(),
+                        utterance: talk.utterance,
+                        start: talk.start,
+                    });
+                    state.talk.insert(talk)
+                }
+                None => continue,
+            };
+
+            talk.speaker = speaker.clone();
+            talk.frames += 1;
+            if self.collect {
+                talk.pcm.extend_from_slice(frame);
+                talk.celt.extend_from_slice(packet);
+            }
+            if active {
+                talk.last_active = now;
+                talk.active_frames = talk.frames;
+            }
+
+            if talk.frames >= max_frames {
+                events.extend(state.talk.take().map(Talk::end));
+            }
+        }
+
+        events
+    }
+
+    /// Ends the utterances of speakers who stayed quiet for the gap by `now`.
+    pub fn tick(&mut self, now: Instant) -> Vec<Event> {
+        let gap = self.gap;
+        self.speakers
+            .values_mut()
+            .filter(|state| {
+                state
+                    .talk
+                    .as_ref()
+                    .map(|talk| now.saturating_duration_since(talk.last_active) >= gap)
+                    .unwrap_or(false)
+            })
+            .filter_map(|state| state.talk.take().map(Talk::end))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segmenter(talk_gap_ms: u32, vad_level: u32) -> Segmenter {
+        Segmenter::new(&RecvVoiceRequest {
+            utterances: true,
+            talk_gap_ms,
+            vad_level,
+            ..RecvVoiceRequest::default()
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn speaker(client_index: i32) -> Speaker {
+        Speaker {
+            client_index,
+            ..Speaker::default()
+        }
+    }
+
+    /// `levels.len()` frames of constant samples at the given levels, with their packets.
+    fn frames(levels: &[i16]) -> (Vec<i16>, Vec<u8>) {
+        let pcm = levels
+            .iter()
+            .flat_map(|level| vec![*level; coder::FRAME_SIZE])
+            .collect();
+        (pcm, vec![0; levels.len() * coder::PACKET_SIZE])
+    }
+
+    /// Utterance numbers of the events, with the frames kept by those that ended.
+    fn summary(events: &[Event]) -> Vec<(&'static str, u64, usize)> {
+        events
+            .iter()
+            .map(|event| match event {
+                Event::Start { utterance, .. } => ("start", *utterance, 0),
+                Event::End { utterance, pcm, .. } => {
+                    ("end", *utterance, pcm.len() / coder::FRAME_SIZE)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn new_checks_the_request() {
+        assert!(Segmenter::new(&RecvVoiceRequest::default())
+            .unwrap()
+            .is_none());
+        assert!(Segmenter::new(&RecvVoiceRequest {
+            utterances: true,
+            continuous: true,
+            ..RecvVoiceRequest::default()
+        })
+        .is_err());
+        assert!(Segmenter::new(&RecvVoiceRequest {
+            talk_events: true,
+            talk_gap_ms: MAX_GAP.as_millis() as u32 + 1,
+            ..RecvVoiceRequest::default()
+        })
+        .is_err());
+
+        assert_eq!(segmenter(0, 0).gap, DEFAULT_GAP);
+        assert_eq!(segmenter(500, 0).gap, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn utterances_end_after_the_gap_without_trailing_quiet() {
+        let now = Instant::now();
+        let mut segmenter = segmenter(300, 100);
+        let (pcm, celt) = frames(&[0, 1000, 1000, 0, 0]);
+
+        let events = segmenter.push(&speaker(1), 2, &pcm, &celt, now);
+        assert_eq!(summary(&events), vec![("start", 0, 0)]);
+        assert!(segmenter.tick(now + Duration::from_millis(299)).is_empty());
+
+        let events = segmenter.tick(now + Duration::from_millis(300));
+        assert_eq!(summary(&events), vec![("end", 0, 2)]);
+        match &events[0] {
+            Event::End { duration, celt, .. } => {
+                // Two frames of 512 samples at 22050 Hz.
+                assert_eq!(duration.as_micros(), 46439);
+                assert_eq!(celt.len(), 2 * coder::PACKET_SIZE);
+            }
+            Event::Start { .. } => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn packets_after_the_gap_start_the_next_utterance() {
+        let now = Instant::now();
+        let mut segmenter = segmenter(300, 0);
+        let (pcm, celt) = frames(&[1000]);
+
+        segmenter.push(&speaker(1), 2, &pcm, &celt, now);
+        let events = segmenter.push(
+            &speaker(1),
+            2,
+            &pcm,
+            &celt,
+            now + Duration::from_millis(100),
+        );
+        assert!(events.is_empty());
+
+        let events = segmenter.push(
+            &speaker(1),
+            2,
+            &pcm,
+            &celt,
+            now + Duration::from_millis(400),
+        );
+        assert_eq!(summary(&events), vec![("end", 0, 2), ("start", 1, 0)]);
+    }
+
+    #[test]
+    fn a_new_userid_in_the_slot_ends_the_old_speakers_utterance() {
+        let now = Instant::now();
+        let mut segmenter = segmenter(300, 0);
+        let (pcm, celt) = frames(&[1000]);
+
+        segmenter.push(&speaker(1), 2, &pcm, &celt, now);
+        let events = segmenter.push(&speaker(1), 3, &pcm, &celt, now);
+        assert_eq!(summary(&events), vec![("end", 0, 1), ("start", 0, 0)]);
+    }
+
+    #[test]
+    fn long_speech_is_split_at_the_longest_utterance() {
+        let now = Instant::now();
+        let mut segmenter = segmenter(300, 0);
+        let max_frames =
+            (MAX_UTTERANCE.as_secs() * coder::SAMPLE_RATE as u64) as usize / coder::FRAME_SIZE;
+        let (pcm, celt) = frames(&vec![1000; max_frames + 1]);
+
+        let events = segmenter.push(&speaker(1), 2, &pcm, &celt, now);
+        assert_eq!(
+            summary(&events),
+            vec![("start", 0, 0), ("end", 0, max_frames), ("start", 1, 0)]
+        );
+    }
+
+    #[test]
+    fn quiet_frames_do_not_start_an_utterance() {
+        let now = Instant::now();
+        let mut segmenter = segmenter(300, 100);
+        let (pcm, celt) = frames(&[0, 50]);
+
+        assert!(segmenter.push(&speaker(1), 2, &pcm, &celt, now).is_empty());
+        assert!(segmenter.tick(now + DEFAULT_GAP).is_empty());
+    }
+}